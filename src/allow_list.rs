@@ -1,40 +1,182 @@
-use anyhow::Result;
-use ipfs_embed::Cid;
+use anyhow::{anyhow, Result};
+use cached::{Cached, TimedCache};
+use chrono::Utc;
+use ipfs_embed::{Cid, Multiaddr, PeerId};
 use libipld::multibase::Base;
-use reqwest::get;
+use libp2p::identity::Keypair;
+use reqwest::Client;
+use rocket::tokio::{fs, sync::Mutex};
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use ssi::did::DIDURL;
+use std::{collections::HashMap as Map, path::PathBuf};
 
 #[rocket::async_trait]
 pub trait OrbitAllowList {
-    async fn is_allowed(&self, oid: &Cid) -> Result<Vec<DIDURL>>;
+    async fn is_allowed(&self, oid: &Cid) -> Result<AllowListEntry>;
+}
+
+/// Parameters an allowlist sets for a newly hosted orbit.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AllowListEntry {
+    #[serde(default)]
+    pub controllers: Vec<DIDURL>,
+    #[serde(default)]
+    pub read_delegators: Vec<DIDURL>,
+    #[serde(default)]
+    pub write_delegators: Vec<DIDURL>,
+    #[serde(default)]
+    #[serde_as(as = "Map<DisplayFromStr, _>")]
+    pub hosts: Map<PeerId, Vec<Multiaddr>>,
+    // maximum storage in bytes for the orbit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+}
+
+// older allowlists respond with just the list of controllers
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AllowListResponse {
+    Entry(AllowListEntry),
+    Controllers(Vec<DIDURL>),
+}
+
+impl From<AllowListResponse> for AllowListEntry {
+    fn from(r: AllowListResponse) -> Self {
+        match r {
+            AllowListResponse::Entry(e) => e,
+            AllowListResponse::Controllers(controllers) => Self {
+                controllers,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn default_ttl() -> u64 {
+    60
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(from = "String", into = "String")]
-pub struct OrbitAllowListService(pub String);
+#[serde(untagged)]
+pub enum OrbitAllowListService {
+    // plain endpoint URL, kept for existing configs
+    Url(String),
+    Remote {
+        url: String,
+        // seconds for which responses are cached
+        #[serde(default = "default_ttl")]
+        ttl: u64,
+    },
+    // JSON object of orbit IDs (b58) to entries, for offline deployments
+    File {
+        path: PathBuf,
+        #[serde(default = "default_ttl")]
+        ttl: u64,
+    },
+}
 
-impl From<String> for OrbitAllowListService {
-    fn from(s: String) -> Self {
-        Self(s)
+impl OrbitAllowListService {
+    pub fn ttl(&self) -> u64 {
+        match self {
+            Self::Url(_) => default_ttl(),
+            Self::Remote { ttl, .. } | Self::File { ttl, .. } => *ttl,
+        }
     }
 }
 
-impl From<OrbitAllowListService> for String {
-    fn from(oals: OrbitAllowListService) -> Self {
-        oals.0
+/// Runtime allowlist, authenticates the node to remote services and caches their responses.
+pub struct AllowList {
+    service: OrbitAllowListService,
+    key: Keypair,
+    cache: Mutex<TimedCache<Cid, AllowListEntry>>,
+}
+
+impl AllowList {
+    pub fn new(service: OrbitAllowListService, key: Keypair) -> Self {
+        let cache = Mutex::new(TimedCache::with_lifespan(service.ttl()));
+        Self {
+            service,
+            key,
+            cache,
+        }
+    }
+
+    async fn fetch(&self, oid: &str) -> Result<AllowListEntry> {
+        Ok(match &self.service {
+            OrbitAllowListService::Url(url) | OrbitAllowListService::Remote { url, .. } => {
+                let url = [url.as_str(), oid].join("/");
+                // the service can identify the node by its peer ID and check the signature over the request
+                let timestamp = Utc::now().timestamp().to_string();
+                let signature = self
+                    .key
+                    .sign([timestamp.as_str(), &url].join("\n").as_bytes())?;
+                Client::new()
+                    .get(&url)
+                    .header(
+                        "x-kepler-peer-id",
+                        self.key.public().into_peer_id().to_string(),
+                    )
+                    .header("x-kepler-timestamp", timestamp)
+                    .header(
+                        "x-kepler-signature",
+                        base64::encode_config(signature, base64::URL_SAFE_NO_PAD),
+                    )
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<AllowListResponse>()
+                    .await?
+                    .into()
+            }
+            OrbitAllowListService::File { path, .. } => {
+                serde_json::from_slice::<Map<String, AllowListResponse>>(&fs::read(path).await?)?
+                    .remove(oid)
+                    .ok_or_else(|| anyhow!("Orbit not in allowlist"))?
+                    .into()
+            }
+        })
     }
 }
 
 #[rocket::async_trait]
-impl OrbitAllowList for OrbitAllowListService {
-    async fn is_allowed(&self, oid: &Cid) -> Result<Vec<DIDURL>> {
-        Ok(
-            get([self.0.as_str(), &oid.to_string_of_base(Base::Base58Btc)?].join("/"))
-                .await?
-                .error_for_status()?
-                .json::<Vec<DIDURL>>()
-                .await?,
-        )
+impl OrbitAllowList for AllowList {
+    async fn is_allowed(&self, oid: &Cid) -> Result<AllowListEntry> {
+        if let Some(entry) = self.cache.lock().await.cache_get(oid) {
+            return Ok(entry.clone());
+        };
+        let entry = self.fetch(&oid.to_string_of_base(Base::Base58Btc)?).await?;
+        self.cache.lock().await.cache_set(*oid, entry.clone());
+        Ok(entry)
     }
 }
+
+#[test]
+fn responses() {
+    let legacy: AllowListEntry = serde_json::from_str::<AllowListResponse>(
+        r#"["did:key:z6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom#z6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom"]"#,
+    )
+    .unwrap()
+    .into();
+    assert_eq!(legacy.controllers.len(), 1);
+    assert!(legacy.quota.is_none());
+
+    let entry: AllowListEntry = serde_json::from_str::<AllowListResponse>(
+        r#"{"controllers": [], "write_delegators": ["did:pkh:tz:tz1YSb7gXhgBw46nSXthhoSzhJdbQf9h92Gy"], "quota": 1024}"#,
+    )
+    .unwrap()
+    .into();
+    assert_eq!(entry.write_delegators.len(), 1);
+    assert_eq!(entry.quota, Some(1024));
+
+    let service: OrbitAllowListService =
+        serde_json::from_str(r#"{"path": "/etc/kepler/allowlist.json"}"#).unwrap();
+    assert!(matches!(
+        service,
+        OrbitAllowListService::File { ttl: 60, .. }
+    ));
+    let service: OrbitAllowListService =
+        serde_json::from_str(r#""http://localhost:10000""#).unwrap();
+    assert!(matches!(service, OrbitAllowListService::Url(_)));
+}
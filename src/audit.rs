@@ -0,0 +1,378 @@
+//! Append-only log of the authorized actions on an orbit.
+//!
+//! Entries are stored as JSON lines in the orbit's directory. When chaining is enabled each entry
+//! carries the hash of the previous one and its own, so edits to the log can be detected.
+
+use crate::auth::{Action, AuthorizationToken};
+use crate::config;
+use crate::orbit::AuthTokens;
+use anyhow::Result;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use libipld::cid::Cid;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Status,
+    tokio::{
+        fs::{self, OpenOptions},
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        sync::Mutex,
+    },
+    Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub seq: u64,
+    // unix timestamp
+    pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invoker: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delegation: Option<String>,
+    pub action: String,
+    // keys or CIDs the action touched
+    #[serde(default)]
+    pub targets: Vec<String>,
+    // HTTP status of the response
+    pub result: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    // why the action was refused, for refusals which don't reach an orbit's log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AuditEntry {
+    /// Records an invocation, the result is set once the request is answered.
+    pub fn new(token: &AuthTokens, targets: Vec<String>) -> Self {
+        let action = match token.action() {
+            Action::Put(_) => "put",
+            Action::Get(_) => "get",
+            Action::Del(_) => "del",
+            Action::List => "list",
+            Action::Create { .. } => "create",
+            Action::Revoke(_) => "revoke",
+            Action::Delete { leave: false } => "delete",
+            Action::Delete { leave: true } => "leave",
+            Action::Snapshot(_) => "snapshot",
+            Action::Update(_) => "update",
+        };
+        let targets = match token.action() {
+            Action::Revoke(ids) if targets.is_empty() => ids.clone(),
+            Action::Snapshot(label) if targets.is_empty() => vec![label.clone()],
+            _ => targets,
+        };
+        Self {
+            seq: 0,
+            timestamp: Utc::now().timestamp(),
+            invoker: token.invoker().ok().map(|i| i.to_string()),
+            delegation: token.delegation_id(),
+            action: action.into(),
+            targets,
+            result: 0,
+            prev: None,
+            hash: None,
+            reason: None,
+        }
+    }
+
+    /// Records an orbit creation refused by the node's policy.
+    pub fn refused(orbit: &Cid, invoker: Option<String>, reason: String) -> Self {
+        Self {
+            seq: 0,
+            timestamp: Utc::now().timestamp(),
+            invoker,
+            delegation: None,
+            action: "create".into(),
+            targets: vec![orbit.to_string()],
+            result: Status::Forbidden.code,
+            prev: None,
+            hash: None,
+            reason: Some(reason),
+        }
+    }
+
+    // hash of the entry with its previous hash, but without its own
+    fn digest(&self) -> Result<String> {
+        let mut entry = self.clone();
+        entry.hash = None;
+        Ok(blake3::hash(&serde_json::to_vec(&entry)?)
+            .to_hex()
+            .to_string())
+    }
+}
+
+/// Filters of a log query, all optional.
+#[derive(Default, Debug, Clone)]
+pub struct AuditQuery {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    // a DID also matches its verification methods
+    pub actor: Option<String>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.from.map(|f| entry.timestamp >= f).unwrap_or(true)
+            && self.to.map(|t| entry.timestamp <= t).unwrap_or(true)
+            && self
+                .actor
+                .as_ref()
+                .map(|a| {
+                    entry
+                        .invoker
+                        .as_ref()
+                        .map(|i| i == a || i.starts_with(&[a, "#"].concat()))
+                        .unwrap_or(false)
+                })
+                .unwrap_or(true)
+    }
+}
+
+// next sequence number and hash of the last entry, with the length of the file they were read
+// from, which is read again if the file changed since
+type LogState = Arc<Mutex<Option<(u64, Option<String>, u64)>>>;
+
+lazy_static! {
+    // shared by all instances of a log, so appends through any of them are serialized
+    static ref STATES: std::sync::Mutex<HashMap<PathBuf, LogState>> = Default::default();
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    state: LogState,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        let state = STATES
+            .lock()
+            .map(|mut states| states.entry(path.clone()).or_default().clone())
+            .unwrap_or_default();
+        Self { path, state }
+    }
+
+    /// Log of the node itself, for actions on orbits which don't exist on it.
+    pub fn node(config: &config::Config) -> Self {
+        Self::new(config.database.path.join("audit_log"))
+    }
+
+    // entries in the order they were appended, read a line at a time until `f` returns false
+    async fn for_each(&self, mut f: impl FnMut(AuditEntry) -> Result<bool>) -> Result<()> {
+        let file = match fs::File::open(&self.path).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        while let Some(line) = lines.next_line().await? {
+            if !line.is_empty() && !f(serde_json::from_str(&line)?)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn append(&self, mut entry: AuditEntry, chain: bool) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let len = match fs::metadata(&self.path).await {
+            Ok(m) => m.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let (seq, last) = match state.take() {
+            Some((seq, last, read)) if read == len => (seq, last),
+            _ => {
+                let mut last = None;
+                self.for_each(|e| {
+                    last = Some(e);
+                    Ok(true)
+                })
+                .await?;
+                match last {
+                    Some(e) => (e.seq + 1, e.hash),
+                    None => (0, None),
+                }
+            }
+        };
+        entry.seq = seq;
+        if chain {
+            entry.prev = last;
+            entry.hash = Some(entry.digest()?);
+        };
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        *state = Some((seq + 1, entry.hash, len + line.len() as u64));
+        Ok(())
+    }
+
+    pub async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+        let mut entries = vec![];
+        self.for_each(|e| {
+            if query.matches(&e) {
+                entries.push(e);
+            };
+            Ok(true)
+        })
+        .await?;
+        Ok(entries)
+    }
+
+    /// Checks the hashes of the chained entries, returning the sequence number of the first
+    /// entry which doesn't match.
+    pub async fn verify(&self) -> Result<Option<u64>> {
+        let (mut last, mut broken): (Option<String>, _) = (None, None);
+        self.for_each(|entry| {
+            if let Some(hash) = &entry.hash {
+                if entry.prev != last || &entry.digest()? != hash {
+                    broken = Some(entry.seq);
+                    return Ok(false);
+                }
+            };
+            last = entry.hash;
+            Ok(true)
+        })
+        .await?;
+        Ok(broken)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditReport {
+    pub entries: Vec<AuditEntry>,
+    // sequence number of the first entry breaking the hash chain of the whole log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broken: Option<u64>,
+}
+
+/// Reports an orbit creation refused by the node's policy, and appends it to the node's log when
+/// auditing is enabled.
+pub async fn refused_creation(
+    config: &config::Config,
+    orbit: &Cid,
+    invoker: Option<String>,
+    reason: &anyhow::Error,
+) {
+    tracing::warn!("refused to create orbit {}: {}", orbit, reason);
+    if !config.audit.enabled {
+        return;
+    };
+    let entry = AuditEntry::refused(orbit, invoker, reason.to_string());
+    if let Err(e) = AuditLog::node(config)
+        .append(entry, config.audit.chain)
+        .await
+    {
+        tracing::error!("failed to append to audit log: {}", e);
+    }
+}
+
+// an invocation authorized by a request guard, waiting for the response
+pub(crate) struct PendingAudit(pub Option<(AuditLog, AuditEntry)>);
+
+/// Appends the invocations of answered requests to the audit logs of their orbits.
+pub struct Audit;
+
+#[rocket::async_trait]
+impl Fairing for Audit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let config = match req.rocket().state::<config::Config>() {
+            Some(c) if c.audit.enabled => &c.audit,
+            _ => return,
+        };
+        if let PendingAudit(Some((log, entry))) = req.local_cache(|| PendingAudit(None)) {
+            let mut entry = entry.clone();
+            entry.result = res.status().code;
+            if let Err(e) = log.append(entry, config.chain).await {
+                tracing::error!("failed to append to audit log: {}", e);
+            }
+        }
+    }
+}
+
+#[test]
+async fn chained_log() -> Result<()> {
+    let tmp = tempdir::TempDir::new("audit")?;
+    let log = AuditLog::new(tmp.path().join("audit_log"));
+    let entry = |invoker: &str, timestamp| AuditEntry {
+        seq: 0,
+        timestamp,
+        invoker: Some(invoker.into()),
+        delegation: None,
+        action: "put".into(),
+        targets: vec!["key".into()],
+        result: 200,
+        prev: None,
+        hash: None,
+        reason: None,
+    };
+    log.append(entry("did:example:alice", 10), true).await?;
+    log.append(entry("did:example:bob", 20), true).await?;
+
+    // the sequence continues from the file when the log is reopened
+    let log = AuditLog::new(tmp.path().join("audit_log"));
+    log.append(entry("did:example:alice#key", 30), true).await?;
+    let all = log.query(&Default::default()).await?;
+    assert_eq!(all.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert_eq!(all[1].prev, all[0].hash);
+    assert_eq!(log.verify().await?, None);
+
+    // instances of the same log which were both used append in sequence
+    let other = AuditLog::new(tmp.path().join("audit_log"));
+    other.append(entry("did:example:bob", 40), true).await?;
+    log.append(entry("did:example:alice", 50), true).await?;
+    let all = log.query(&Default::default()).await?;
+    assert_eq!(
+        all.iter().map(|e| e.seq).collect::<Vec<_>>(),
+        vec![0, 1, 2, 3, 4]
+    );
+    assert_eq!(all[4].prev, all[3].hash);
+    assert_eq!(log.verify().await?, None);
+
+    let alice = log
+        .query(&AuditQuery {
+            actor: Some("did:example:alice".into()),
+            from: Some(15),
+            to: Some(45),
+        })
+        .await?;
+    assert_eq!(alice.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![2]);
+
+    let tampered = fs::read_to_string(tmp.path().join("audit_log"))
+        .await?
+        .replace("did:example:bob", "did:example:eve");
+    fs::write(tmp.path().join("audit_log"), tampered).await?;
+    assert_eq!(log.verify().await?, Some(1));
+
+    // refused creations are logged by the node, with the reason they were refused
+    let oid: Cid = "zCT5htkeBtA6Qu5YF4vPkQcfeqy3pY4m8zxGdUKUiPgtPEbY3rHy".parse()?;
+    let node = AuditLog::new(tmp.path().join("node_log"));
+    let reason = "Orbit method tz not allowed".to_string();
+    node.append(AuditEntry::refused(&oid, None, reason.clone()), true)
+        .await?;
+    let refused = node.query(&Default::default()).await?;
+    assert_eq!(refused[0].reason, Some(reason));
+    assert_eq!(refused[0].result, 403);
+    assert_eq!(node.verify().await?, None);
+    Ok(())
+}
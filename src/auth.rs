@@ -1,16 +1,25 @@
+use crate::allow_list::AllowList;
+use crate::audit::{refused_creation, AuditEntry, AuditLog, PendingAudit};
 use crate::cas::CidWrap;
 use crate::config;
-use crate::orbit::{create_orbit, get_metadata, load_orbit, AuthTokens, Orbit};
+use crate::geometry::check_geometry;
+use crate::host_keys::HostKeys;
+use crate::orbit::{
+    check_creation_policy, create_orbit, get_metadata, load_orbit, lock_creation, AuthTokens, Orbit,
+};
 use crate::relay::RelayNode;
+use crate::tz::{check_replay, NonceCache};
 use anyhow::Result;
-use ipfs_embed::{Keypair, Multiaddr, PeerId};
+use chrono::Utc;
+use ipfs_embed::{Multiaddr, PeerId};
 use libipld::cid::Cid;
 use rocket::{
-    http::Status,
+    http::{Method, Status},
     request::{FromRequest, Outcome, Request},
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::RwLock};
+use ssi::did::DIDURL;
+use std::{path::PathBuf, str::FromStr};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -23,11 +32,65 @@ pub enum Action {
         content: Vec<String>,
     },
     List,
+    // IDs of delegations to revoke
+    Revoke(Vec<String>),
+    // remove the orbit from all of its hosts, or only stop hosting it on this node when leaving
+    Delete {
+        leave: bool,
+    },
+    // label for the current heads of the orbit's S3 store
+    Snapshot(String),
+    // manifest changes as matrix parameters, e.g. `public=true`
+    Update(String),
+}
+
+impl Action {
+    /// Keys, key prefixes or CIDs which a content action is scoped to
+    pub fn content(&self) -> &[String] {
+        match self {
+            Self::Put(content) | Self::Get(content) | Self::Del(content) => content,
+            _ => &[],
+        }
+    }
+
+    pub fn covers(&self, target: &str) -> bool {
+        match self {
+            // a token listing no content is valid for the whole orbit
+            Self::Put(content) | Self::Get(content) | Self::Del(content) => {
+                content.is_empty() || content.iter().any(|scope| scope_covers(scope, target))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// A scope is either an exact key or CID, or a key prefix ending with `*`
+pub fn scope_covers(scope: &str, target: &str) -> bool {
+    match scope.strip_suffix('*') {
+        Some(prefix) => target.starts_with(prefix),
+        None => {
+            scope == target
+                || match (Cid::from_str(scope), Cid::from_str(target)) {
+                    (Ok(s), Ok(t)) => s == t,
+                    _ => false,
+                }
+        }
+    }
+}
+
+/// Whether everything covered by the `inner` scope is also covered by `outer`
+pub fn scope_within(outer: &str, inner: &str) -> bool {
+    match (outer.strip_suffix('*'), inner.strip_suffix('*')) {
+        (Some(o), Some(i)) => i.starts_with(o),
+        (None, Some(_)) => false,
+        (_, None) => scope_covers(outer, inner),
+    }
 }
 
 pub trait AuthorizationToken {
     fn action(&self) -> &Action;
     fn target_orbit(&self) -> &Cid;
+    fn invoker(&self) -> Result<DIDURL>;
 }
 
 #[rocket::async_trait]
@@ -40,6 +103,22 @@ pub struct GetAuthWrapper(pub Orbit);
 pub struct DelAuthWrapper(pub Orbit);
 pub struct CreateAuthWrapper(pub Orbit);
 pub struct ListAuthWrapper(pub Orbit);
+// the audit log of an orbit is never public, even when its content is
+pub struct AuditAuthWrapper(pub Orbit);
+pub struct RevokeAuthWrapper(pub Orbit);
+pub struct DeleteOrbitAuthWrapper(pub Orbit);
+pub struct SnapshotAuthWrapper(pub Orbit);
+pub struct UpdateAuthWrapper(pub Orbit);
+
+// tokens which are signed once and sent as-is must be recent and addressed to this node
+fn check_freshness(token: &AuthTokens, auth: &config::Auth, nonces: &NonceCache) -> Result<()> {
+    match token {
+        AuthTokens::Tezos(t) => check_replay(t, auth, nonces),
+        AuthTokens::VP(t) => t.check_replay(auth, nonces, Utc::now()),
+        AuthTokens::UCAN(t) => t.check_freshness(auth, Utc::now().timestamp()),
+        _ => Ok(()),
+    }
+}
 
 async fn extract_info<T>(
     req: &Request<'_>,
@@ -67,10 +146,20 @@ async fn extract_info<T>(
             )));
         }
     };
-    match AuthTokens::from_request(req).await {
-        Outcome::Success(token) => {
-            Ok((auth_data.as_bytes().to_vec(), token, config.clone(), relay))
+    let nonces = match req.rocket().state::<NonceCache>() {
+        Some(n) => n,
+        None => {
+            return Err(Outcome::Failure((
+                Status::InternalServerError,
+                anyhow!("Could not retrieve nonce cache"),
+            )));
         }
+    };
+    match AuthTokens::from_request(req).await {
+        Outcome::Success(token) => match check_freshness(&token, &config.auth, nonces) {
+            Ok(()) => Ok((auth_data.as_bytes().to_vec(), token, config.clone(), relay)),
+            Err(e) => Err(Outcome::Failure((Status::Unauthorized, e))),
+        },
         Outcome::Failure(e) => Err(Outcome::Failure(e)),
         Outcome::Forward(_) => Err(Outcome::Failure((
             Status::Unauthorized,
@@ -79,15 +168,170 @@ async fn extract_info<T>(
     }
 }
 
+// orbits with public reads are served without a token, any other orbit falls back to tokens
+async fn public_orbit<T>(req: &Request<'_>) -> Result<Option<Orbit>, Outcome<T, anyhow::Error>> {
+    let (config, relay) = match (
+        req.rocket().state::<config::Config>(),
+        req.rocket().state::<RelayNode>(),
+    ) {
+        (Some(c), Some(r)) => (c, (r.id, r.internal())),
+        _ => {
+            return Err(Outcome::Failure((
+                Status::InternalServerError,
+                anyhow!("Could not retrieve node configuration"),
+            )));
+        }
+    };
+    let oid: Cid = match req.param::<CidWrap>(0) {
+        Some(Ok(o)) => o.0,
+        _ => {
+            return Err(Outcome::Failure((
+                Status::InternalServerError,
+                anyhow!("Could not parse orbit"),
+            )));
+        }
+    };
+    match load_orbit(oid, config.database.path.clone(), relay).await {
+        Ok(Some(orbit)) => orbit
+            .public()
+            .await
+            .map_err(|e| Outcome::Failure((Status::InternalServerError, e))),
+        // missing orbits are reported by the token path
+        Ok(None) => Ok(None),
+        Err(e) => Err(Outcome::Failure((Status::InternalServerError, e))),
+    }
+}
+
+// the S3 key or CID a request operates on, if any
+fn request_target(req: &Request<'_>) -> Option<String> {
+    match req.routed_segment(1) {
+        // keys are addressed the same way in the current state and at a snapshot
+        Some(s) if s == "s3" || s.starts_with("s3@") => req
+            .segments::<PathBuf>(2..)
+            .ok()
+            .and_then(|key| key.to_str().map(String::from))
+            .filter(|key| !key.is_empty()),
+        // content of the orbit's CAS, the orbit's other routes address no content
+        Some(cid) if req.routed_segment(2).is_none() && Cid::from_str(cid).is_ok() => {
+            Some(cid.to_string())
+        }
+        _ => None,
+    }
+}
+
+// the invocation is recorded in the orbit's audit log once the response status is known, the
+// log of a deleted orbit goes with it so deletions are recorded by the node
+fn stash_audit(req: &Request<'_>, config: &config::Config, orbit: &Orbit, token: &AuthTokens) {
+    let targets = match (request_target(req), token.action()) {
+        (Some(target), _) => vec![target],
+        (None, Action::Delete { .. }) => vec![orbit.id().to_string()],
+        (None, action) => action.content().to_vec(),
+    };
+    let log = match token.action() {
+        Action::Delete { .. } => AuditLog::node(config),
+        _ => orbit.audit().clone(),
+    };
+    let entry = AuditEntry::new(token, targets);
+    req.local_cache(|| PendingAudit(Some((log, entry))));
+}
+
+/// The content action a request performs, derived from its method and path.
+pub fn requested_action(req: &Request<'_>) -> Option<Action> {
+    match (req.method(), request_target(req)) {
+        (Method::Get, None) => Some(Action::List),
+        (Method::Get, Some(target)) | (Method::Head, Some(target)) => {
+            Some(Action::Get(vec![target]))
+        }
+        (Method::Put, target) => Some(Action::Put(target.into_iter().collect())),
+        (Method::Delete, Some(target)) => Some(Action::Del(vec![target])),
+        _ => None,
+    }
+}
+
+// writes are refused once the orbit would exceed its quota, the declared body size counts towards
+// it and bodies are not read past it, so writes of an unknown size are refused
+fn check_quota(req: &Request<'_>, orbit: &Orbit) -> Result<(), (Status, anyhow::Error)> {
+    let quota = match orbit.metadata().quota {
+        Some(q) => q,
+        None => return Ok(()),
+    };
+    let incoming = req
+        .headers()
+        .get_one("Content-Length")
+        .and_then(|l| l.parse::<u64>().ok())
+        .ok_or_else(|| {
+            (
+                Status::LengthRequired,
+                anyhow!("Writes to an orbit with a quota must declare their length"),
+            )
+        })?;
+    let used = orbit
+        .storage_size()
+        .map_err(|e| (Status::InternalServerError, e))?;
+    if used + incoming > quota {
+        Err((
+            Status::InsufficientStorage,
+            anyhow!("Orbit storage quota of {} bytes exceeded", quota),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Access to the node's admin routes, granted by the configured shared secret.
+pub struct AdminAuth;
+
+// compares every byte, so the secret can't be guessed from response timings
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAuth {
+    type Error = anyhow::Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let expected = match req
+            .rocket()
+            .state::<config::Config>()
+            .and_then(|c| c.admin.token.as_ref())
+        {
+            Some(t) => t,
+            None => {
+                return Outcome::Failure((
+                    Status::Forbidden,
+                    anyhow!("Admin access is not configured"),
+                ))
+            }
+        };
+        match req.headers().get_one("x-kepler-admin-token") {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+                Outcome::Success(Self)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, anyhow!("Invalid admin token"))),
+        }
+    }
+}
+
 // TODO some APIs prefer to return 404 when the authentication fails to avoid leaking information about content
 
 macro_rules! impl_fromreq {
     ($type:ident, $method:tt) => {
+        impl_fromreq!($type, $method, false);
+    };
+    ($type:ident, $method:tt, $public:expr) => {
         #[rocket::async_trait]
         impl<'r> FromRequest<'r> for $type {
             type Error = anyhow::Error;
 
             async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+                if $public {
+                    match public_orbit(req).await {
+                        Ok(Some(orbit)) => return Outcome::Success(Self(orbit)),
+                        Ok(None) => (),
+                        Err(o) => return o,
+                    }
+                };
                 let (_, token, config, relay) = match extract_info(req).await {
                     Ok(i) => i,
                     Err(o) => return o,
@@ -107,6 +351,14 @@ macro_rules! impl_fromreq {
                         anyhow!("Token target orbit not matching endpoint"),
                     )),
                     (Action::$method { .. }, true) => {
+                        if let Some(target) = request_target(req) {
+                            if !token.action().covers(&target) {
+                                return Outcome::Failure((
+                                    Status::Unauthorized,
+                                    anyhow!("Token not scoped to {}", target),
+                                ));
+                            }
+                        };
                         let orbit = match load_orbit(
                             *token.target_orbit(),
                             config.database.path.clone(),
@@ -123,10 +375,17 @@ macro_rules! impl_fromreq {
                             }
                             Err(e) => return Outcome::Failure((Status::InternalServerError, e)),
                         };
-                        match orbit.authorize(&token).await {
-                            Ok(_) => Outcome::Success(Self(orbit)),
-                            Err(e) => Outcome::Failure((Status::Unauthorized, e)),
-                        }
+                        stash_audit(req, &config, &orbit, &token);
+                        let orbit = match orbit.authorized(&token).await {
+                            Ok(o) => o,
+                            Err(e) => return Outcome::Failure((Status::Unauthorized, e)),
+                        };
+                        if let Action::Put(_) = token.action() {
+                            if let Err(e) = check_quota(req, &orbit) {
+                                return Outcome::Failure(e);
+                            }
+                        };
+                        Outcome::Success(Self(orbit))
                     }
                     _ => Outcome::Failure((
                         Status::BadRequest,
@@ -139,9 +398,14 @@ macro_rules! impl_fromreq {
 }
 
 impl_fromreq!(PutAuthWrapper, Put);
-impl_fromreq!(GetAuthWrapper, Get);
+impl_fromreq!(GetAuthWrapper, Get, true);
 impl_fromreq!(DelAuthWrapper, Del);
-impl_fromreq!(ListAuthWrapper, List);
+impl_fromreq!(ListAuthWrapper, List, true);
+impl_fromreq!(AuditAuthWrapper, List);
+impl_fromreq!(RevokeAuthWrapper, Revoke);
+impl_fromreq!(DeleteOrbitAuthWrapper, Delete);
+impl_fromreq!(SnapshotAuthWrapper, Snapshot);
+impl_fromreq!(UpdateAuthWrapper, Update);
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for CreateAuthWrapper {
@@ -152,7 +416,7 @@ impl<'r> FromRequest<'r> for CreateAuthWrapper {
             Ok(i) => i,
             Err(o) => return o,
         };
-        let keys = match req.rocket().state::<RwLock<HashMap<PeerId, Keypair>>>() {
+        let keys = match req.rocket().state::<HostKeys>() {
             Some(k) => k,
             _ => {
                 return Outcome::Failure((
@@ -164,7 +428,6 @@ impl<'r> FromRequest<'r> for CreateAuthWrapper {
 
         match &token.action() {
             // Create actions dont have an existing orbit to authorize against, it's a node policy
-            // TODO have policy config, for now just be very permissive :shrug:
             Action::Create { parameters, .. } => {
                 let md = match get_metadata(token.target_orbit(), parameters, &config.chains).await
                 {
@@ -177,9 +440,67 @@ impl<'r> FromRequest<'r> for CreateAuthWrapper {
                     Err(e) => return Outcome::Failure((Status::Unauthorized, e)),
                 };
 
-                match create_orbit(&md, config.database.path.clone(), &auth_data, relay, keys).await
+                let policy = &config.orbits.creation;
+                let allowlist = match (policy.allowlist, req.rocket().state::<AllowList>()) {
+                    (false, _) => None,
+                    (true, Some(list)) => Some(list),
+                    (true, None) => {
+                        return Outcome::Failure((
+                            Status::InternalServerError,
+                            anyhow!("Allowlist Not Configured"),
+                        ))
+                    }
+                };
+                let _creating = lock_creation().await;
+                let md = match check_creation_policy(
+                    policy,
+                    &md,
+                    parameters,
+                    &config.database.path,
+                    allowlist,
+                )
+                .await
+                {
+                    Ok(Some(entry)) => {
+                        let mut md = md;
+                        md.apply(entry);
+                        md
+                    }
+                    Ok(None) => md,
+                    Err(e) => {
+                        let invoker = token.invoker().ok().map(|i| i.to_string());
+                        refused_creation(&config, &md.id, invoker, &e).await;
+                        return Outcome::Failure((Status::Forbidden, e));
+                    }
+                };
+                let creator = match token.invoker() {
+                    Ok(c) => c,
+                    Err(e) => return Outcome::Failure((Status::Unauthorized, e)),
+                };
+                // a geometry must not expose orbits the creator can't read
+                if let Err(e) = check_geometry(&md, &creator, &config.database.path).await {
+                    return Outcome::Failure((Status::Forbidden, e));
+                };
+
+                let relays = req
+                    .rocket()
+                    .state::<RelayNode>()
+                    .map(|r| r.advertised())
+                    .unwrap_or_default();
+                match create_orbit(
+                    &md,
+                    config.database.path.clone(),
+                    &auth_data,
+                    relay,
+                    &relays,
+                    keys,
+                )
+                .await
                 {
-                    Ok(Some(orbit)) => Outcome::Success(Self(orbit)),
+                    Ok(Some(orbit)) => {
+                        stash_audit(req, &config, &orbit, &token);
+                        Outcome::Success(Self(orbit))
+                    }
                     Ok(None) => {
                         return Outcome::Failure((
                             Status::Conflict,
@@ -196,3 +517,25 @@ impl<'r> FromRequest<'r> for CreateAuthWrapper {
         }
     }
 }
+
+#[test]
+async fn scopes() {
+    assert!(scope_covers("photos/cat.jpg", "photos/cat.jpg"));
+    assert!(!scope_covers("photos/cat.jpg", "photos/dog.jpg"));
+    assert!(scope_covers("photos/*", "photos/dog.jpg"));
+    assert!(!scope_covers("photos/*", "docs/cv.pdf"));
+    // the same CID in different bases
+    assert!(scope_covers(
+        "uAYAEHiB0uGRNPXEMdA9L-lXR2MKIZzKlgW1z6Ug4fSv3LRSPfQ",
+        &Cid::from_str("uAYAEHiB0uGRNPXEMdA9L-lXR2MKIZzKlgW1z6Ug4fSv3LRSPfQ")
+            .unwrap()
+            .to_string()
+    ));
+    assert!(scope_within("photos/*", "photos/2021/*"));
+    assert!(scope_within("photos/*", "photos/cat.jpg"));
+    assert!(!scope_within("photos/2021/*", "photos/*"));
+    assert!(!scope_within("photos/cat.jpg", "photos/*"));
+    // tokens listing no content cover the whole orbit
+    assert!(Action::Put(vec![]).covers("photos/cat.jpg"));
+    assert!(!Action::Put(vec!["docs/*".into()]).covers("photos/cat.jpg"));
+}
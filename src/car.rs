@@ -0,0 +1,265 @@
+//! Orbit archives in the CARv1 format.
+//!
+//! The single root of an archive is an [`Archive`] block recording the orbit's manifest and the
+//! heads of its stores, followed by every block of the orbit's block store.
+
+use crate::{
+    cas::ContentAddressedStorage,
+    host_keys::HostKeys,
+    ipfs::Block,
+    orbit::{create_orbit, Orbit, OrbitMetadata},
+};
+use anyhow::Result;
+use ipfs_embed::{Multiaddr, PeerId};
+use libipld::{cbor::DagCborCodec, cid::Cid, codec::Codec, multihash::Code, DagCbor};
+use rocket::{
+    futures::stream::{self, Stream, StreamExt},
+    tokio::io::{AsyncRead, AsyncReadExt},
+};
+use std::{io::ErrorKind, path::PathBuf};
+
+// largest section read from an archive, well above the block size of the stores
+const MAX_SECTION: usize = 4 << 20;
+
+#[derive(DagCbor)]
+struct CarHeader {
+    roots: Vec<Cid>,
+    version: u64,
+}
+
+#[derive(DagCbor, Debug, Clone)]
+pub struct Archive {
+    // the orbit's metadata, JSON encoded as on disk
+    pub manifest: Vec<u8>,
+    pub heads: Vec<Cid>,
+    pub revocations: Vec<Cid>,
+    pub snapshots: Vec<Cid>,
+    // blocks put through the CAS API, which are kept by alias rather than by the S3 index
+    pub pins: Vec<Cid>,
+}
+
+fn write_varint(mut n: usize, out: &mut Vec<u8>) {
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+// the length prefix of a section, or none at the end of the archive
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<usize>> {
+    let mut n = 0usize;
+    for i in 0..9 {
+        let b = match reader.read_u8().await {
+            Ok(b) => b,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && i == 0 => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(anyhow!("Truncated archive"))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        n |= ((b & 0x7f) as usize) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(Some(n));
+        }
+    }
+    Err(anyhow!("Invalid varint in archive"))
+}
+
+async fn read_section<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let len = match read_varint(reader).await? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    if len > MAX_SECTION {
+        return Err(anyhow!("Archive section of {} bytes is too large", len));
+    };
+    let mut section = vec![0; len];
+    reader
+        .read_exact(&mut section)
+        .await
+        .map_err(|_| anyhow!("Truncated archive"))?;
+    Ok(Some(section))
+}
+
+fn header(root: &Cid) -> Result<Vec<u8>> {
+    let header = DagCborCodec.encode(&CarHeader {
+        roots: vec![*root],
+        version: 1,
+    })?;
+    let mut out = vec![];
+    write_varint(header.len(), &mut out);
+    out.extend(header);
+    Ok(out)
+}
+
+fn section(cid: &Cid, data: &[u8]) -> Vec<u8> {
+    let cid = cid.to_bytes();
+    let mut out = vec![];
+    write_varint(cid.len() + data.len(), &mut out);
+    out.extend(cid);
+    out.extend(data);
+    out
+}
+
+/// Reads an archive a block at a time, checking every block against its CID.
+pub struct CarReader<R> {
+    reader: R,
+    pub roots: Vec<Cid>,
+}
+
+impl<R: AsyncRead + Unpin> CarReader<R> {
+    pub async fn new(mut reader: R) -> Result<Self> {
+        let header: CarHeader = DagCborCodec.decode(
+            &read_section(&mut reader)
+                .await?
+                .ok_or_else(|| anyhow!("Empty archive"))?,
+        )?;
+        if header.version != 1 {
+            return Err(anyhow!("Unsupported archive version {}", header.version));
+        };
+        Ok(Self {
+            reader,
+            roots: header.roots,
+        })
+    }
+
+    pub async fn next_block(&mut self) -> Result<Option<Block>> {
+        let section = match read_section(&mut self.reader).await? {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let mut data = section.as_slice();
+        let cid = Cid::read_bytes(&mut data)?;
+        // fails if the data doesn't hash to the CID
+        Ok(Some(Block::new(cid, data.to_vec())?))
+    }
+}
+
+/// Streams an orbit as an archive, reading its blocks as they are sent.
+pub async fn export(orbit: &Orbit) -> Result<impl Stream<Item = Vec<u8>>> {
+    let ipfs = orbit.service.store.ipfs.clone();
+    let archive = Archive {
+        manifest: serde_json::to_vec(orbit.metadata())?,
+        heads: orbit.service.heads()?,
+        revocations: orbit.revocations.0.heads()?,
+        snapshots: orbit.snapshots.service.heads()?,
+        pins: ContentAddressedStorage::list(&ipfs).await?,
+    };
+    let root = Block::encode(DagCborCodec, Code::Blake3_256, &archive)?;
+    let cids: Vec<Cid> = ipfs.iter()?.collect();
+
+    Ok(
+        stream::iter(vec![header(root.cid())?, section(root.cid(), root.data())]).chain(
+            stream::iter(cids).filter_map(move |cid| {
+                let s = match ipfs.get(&cid) {
+                    Ok(block) => Some(section(block.cid(), block.data())),
+                    Err(e) => {
+                        tracing::error!("failed to export block {}: {}", cid, e);
+                        None
+                    }
+                };
+                async move { s }
+            }),
+        ),
+    )
+}
+
+/// Creates an orbit on this node from an archive, seeding its stores with the archived blocks as
+/// they are read.
+pub async fn import<R: AsyncRead + Unpin>(
+    reader: R,
+    path: PathBuf,
+    relay: (PeerId, Multiaddr),
+    relays: &[Multiaddr],
+    keys: &HostKeys,
+) -> Result<Orbit> {
+    let mut car = CarReader::new(reader).await?;
+    let root = match car.roots.as_slice() {
+        [root] => *root,
+        _ => return Err(anyhow!("Archive must have a single root")),
+    };
+    // `export` writes the root first, so the orbit exists before its other blocks are read
+    let archive: Archive = match car.next_block().await? {
+        Some(block) if block.cid() == &root => block.decode()?,
+        _ => return Err(anyhow!("Archive must start with its root block")),
+    };
+    let md: OrbitMetadata = serde_json::from_slice(&archive.manifest)?;
+
+    let orbit = create_orbit(&md, path, &[], relay.clone(), relays, keys)
+        .await?
+        .ok_or_else(|| anyhow!("Orbit already exists"))?;
+    match seed(&orbit, &mut car, archive).await {
+        Ok(()) => Ok(orbit),
+        Err(e) => {
+            // a partly imported orbit is removed rather than left behind
+            if let Err(e) = orbit.delete(relay, None).await {
+                tracing::error!("failed to remove partly imported orbit: {}", e);
+            };
+            Err(e)
+        }
+    }
+}
+
+async fn seed<R: AsyncRead + Unpin>(
+    orbit: &Orbit,
+    car: &mut CarReader<R>,
+    archive: Archive,
+) -> Result<()> {
+    let ipfs = &orbit.service.store.ipfs;
+    while let Some(block) = car.next_block().await? {
+        ipfs.insert(&block)?;
+    }
+    for pin in archive.pins.iter() {
+        ipfs.alias(pin.to_bytes(), Some(pin))?;
+    }
+    // all blocks are local, so merging rebuilds the indexes without fetching from peers
+    orbit
+        .service
+        .try_merge_heads(archive.heads.into_iter())
+        .await?;
+    orbit
+        .revocations
+        .0
+        .try_merge_heads(archive.revocations.into_iter())
+        .await?;
+    // snapshots are pinned again as they are merged
+    orbit
+        .snapshots
+        .service
+        .try_merge_heads(archive.snapshots.into_iter())
+        .await?;
+    Ok(())
+}
+
+#[test]
+async fn roundtrip() -> Result<()> {
+    use libipld::raw::RawCodec;
+    let blocks = vec![
+        Block::encode(RawCodec, Code::Blake3_256, &b"hello"[..])?,
+        Block::encode(RawCodec, Code::Blake3_256, &vec![7u8; 300][..])?,
+    ];
+    let mut car = header(blocks[0].cid())?;
+    for b in blocks.iter() {
+        car.extend(section(b.cid(), b.data()));
+    }
+
+    let mut reader = CarReader::new(&car[..]).await?;
+    assert_eq!(reader.roots, vec![*blocks[0].cid()]);
+    let mut read_blocks = vec![];
+    while let Some(block) = reader.next_block().await? {
+        read_blocks.push(block);
+    }
+    assert_eq!(read_blocks, blocks);
+
+    // tampered data no longer matches its CID, and a cut archive is refused
+    let last = car.len() - 1;
+    car[last] ^= 1;
+    let mut reader = CarReader::new(&car[..]).await?;
+    assert!(reader.next_block().await.is_ok());
+    assert!(reader.next_block().await.is_err());
+    let mut reader = CarReader::new(&car[..last]).await?;
+    assert!(reader.next_block().await.is_ok());
+    assert!(reader.next_block().await.is_err());
+    Ok(())
+}
@@ -1,5 +1,7 @@
 use crate::allow_list::OrbitAllowListService;
+use libp2p::Multiaddr;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use std::path::PathBuf;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -8,14 +10,46 @@ pub struct Config {
     pub chains: ExternalApis,
     pub orbits: OrbitsConfig,
     pub relay: Relay,
+    pub auth: Auth,
+    pub admin: Admin,
+    pub keys: HostKeys,
+    pub audit: Audit,
+    pub gateway: Gateway,
+    pub metrics: Metrics,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct OrbitsConfig {
-    #[serde(default)]
-    pub public: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub allowlist: Option<OrbitAllowListService>,
+    #[serde(default)]
+    pub creation: CreationPolicy,
+    // the node-wide switch replaced by the `public` flag of orbit manifests, refused rather than
+    // ignored so a node doesn't silently stop serving reads without a token
+    #[serde(default, skip_serializing, deserialize_with = "removed_public")]
+    public: (),
+}
+
+fn removed_public<'de, D: serde::Deserializer<'de>>(_: D) -> Result<(), D::Error> {
+    Err(serde::de::Error::custom(
+        "orbits.public has been removed, make orbits public with the `public` flag of their manifest",
+    ))
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct CreationPolicy {
+    // DIDs or DID method prefixes (e.g. `did:pkh:tz`) of controllers allowed to create orbits, any if empty
+    #[serde(default)]
+    pub controllers: Vec<String>,
+    // orbit methods (e.g. `tz`) allowed for new orbits, any if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // maximum number of orbits hosted for a single controller
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    // new orbits must also be allowed by the allowlist service
+    #[serde(default)]
+    pub allowlist: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -36,10 +70,41 @@ pub struct ExternalApis {
     pub tzkt: Option<String>,
 }
 
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Relay {
+    // IP to listen on when `listen` is empty
     pub address: String,
     pub port: u16,
+    // multiaddrs to listen on, e.g. `/ip6/::/tcp/8081`
+    #[serde(default)]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub listen: Vec<Multiaddr>,
+    // multiaddrs advertised to peers, the listen addresses bound to a specific IP if empty
+    #[serde(default)]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub external: Vec<Multiaddr>,
+    // other relays advertised for the orbits of this node, including their `/p2p/<id>`
+    #[serde(default)]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub relays: Vec<Multiaddr>,
+    #[serde(default)]
+    pub limits: RelayLimits,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RelayLimits {
+    // circuits relayed at once for a single peer
+    pub circuits: Option<u32>,
+    // circuits relayed at once in total
+    pub total: Option<u32>,
+    // seconds a relayed circuit may stay open
+    pub duration: Option<u64>,
+    // bytes a relayed circuit may carry
+    pub bytes: Option<u64>,
+    // only relay for hosts of orbits served by this node
+    #[serde(default)]
+    pub hosts: bool,
 }
 
 impl Default for Relay {
@@ -47,6 +112,125 @@ impl Default for Relay {
         Self {
             address: "127.0.0.1".into(),
             port: 8081,
+            listen: vec![],
+            external: vec![],
+            relays: vec![],
+            limits: Default::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Auth {
+    // public host name of the node, signed messages must be addressed to it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    // seconds a signed message may be timestamped ahead of the node's clock
+    pub skew: u64,
+    // seconds for which a signed message is valid
+    pub lifetime: u64,
+    // reject signed messages for writes which have already been used, presentations always are
+    #[serde(default)]
+    pub nonces: bool,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            domain: None,
+            skew: 300,
+            lifetime: 3600,
+            nonces: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Admin {
+    // shared secret for the admin routes, which are refused when it is unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostKeys {
+    // seconds a key generated with `/peer/generate` stays usable for creating an orbit
+    pub lifetime: u64,
+    // keys pending at the same time
+    pub limit: usize,
+}
+
+impl Default for HostKeys {
+    fn default() -> Self {
+        Self {
+            lifetime: 3600,
+            limit: 100,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Audit {
+    // record authorized actions in a log per orbit, off by default as the logs grow unbounded
+    pub enabled: bool,
+    // link log entries by their hashes so edits can be detected
+    #[serde(default)]
+    pub chain: bool,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            chain: false,
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Gateway {
+    // serve public orbits to browsers at `/orbit/<orbit id>/<key>`
+    pub enabled: bool,
+    // also serve them at `<orbit id>.<domain>/<key>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    // key served for a directory, relative to it
+    pub index: String,
+    // list directories without an index
+    pub listing: bool,
+    // key of the page served for missing keys
+    pub notfound: String,
+}
+
+impl Default for Gateway {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            domain: None,
+            index: "index.html".into(),
+            listing: true,
+            notfound: "404.html".into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Metrics {
+    // export the storage used by each orbit, which lists the orbits hosted on the node
+    pub orbits: bool,
+}
+
+#[test]
+async fn removed_keys() {
+    use rocket::figment::{
+        providers::{Format, Serialized, Toml},
+        Figment,
+    };
+    let config = |toml| {
+        Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(toml))
+            .extract::<Config>()
+    };
+    assert!(config("[orbits.creation]\nlimit = 3").is_ok());
+    assert!(config("[orbits]\npublic = true").is_err());
+}
@@ -0,0 +1,212 @@
+//! Client-side encryption of S3 objects for encrypted orbits.
+//!
+//! Each object is encrypted under a fresh content key before it is uploaded, so hosts only ever
+//! see ciphertext. The content key is wrapped for every reader's X25519 key, and the wrapped keys
+//! travel with the object as `x-kepler-key-<reader>` metadata headers.
+
+use anyhow::Result;
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::collections::BTreeMap;
+pub use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Header naming the encryption scheme, required on writes to encrypted orbits.
+pub const SCHEME_HEADER: &str = "x-kepler-encryption";
+pub const SCHEME: &str = "x25519-chacha20poly1305";
+/// Prefix of the headers holding wrapped content keys, followed by the reader's hex public key.
+pub const KEY_HEADER_PREFIX: &str = "x-kepler-key-";
+
+const WRAP_CONTEXT: &str = "kepler 2021-10 content key wrapping";
+const NONCE_LEN: usize = 12;
+
+fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("{}", e))?;
+    Ok(bytes)
+}
+
+/// Generates a reader key, whose public half content keys are wrapped for.
+pub fn generate_reader_key() -> Result<StaticSecret> {
+    Ok(StaticSecret::from(random::<32>()?))
+}
+
+pub fn key_header(reader: &PublicKey) -> String {
+    [KEY_HEADER_PREFIX, &hex::encode(reader.as_bytes())].concat()
+}
+
+// keys are only ever used once, for one content key or one object
+fn cipher(key: &[u8; 32]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+fn wrapping_key(shared: &[u8], ephemeral: &PublicKey, reader: &PublicKey) -> [u8; 32] {
+    blake3::derive_key(
+        WRAP_CONTEXT,
+        &[shared, ephemeral.as_bytes(), reader.as_bytes()].concat(),
+    )
+}
+
+fn wrap(content_key: &[u8; 32], reader: &PublicKey) -> Result<String> {
+    let ephemeral = StaticSecret::from(random::<32>()?);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(reader);
+    let wrapped = cipher(&wrapping_key(shared.as_bytes(), &ephemeral_public, reader))
+        .encrypt(Nonce::from_slice(&[0; NONCE_LEN]), &content_key[..])
+        .map_err(|_| anyhow!("Failed to wrap content key"))?;
+    Ok(hex::encode(
+        [&ephemeral_public.as_bytes()[..], &wrapped].concat(),
+    ))
+}
+
+fn unwrap(wrapped: &str, key: &StaticSecret) -> Result<[u8; 32]> {
+    let wrapped = hex::decode(wrapped)?;
+    if wrapped.len() < 32 {
+        return Err(anyhow!("Wrapped key too short"));
+    };
+    let (ephemeral, wrapped) = wrapped.split_at(32);
+    let mut ephemeral_bytes = [0u8; 32];
+    ephemeral_bytes.copy_from_slice(ephemeral);
+    let ephemeral = PublicKey::from(ephemeral_bytes);
+    let shared = key.diffie_hellman(&ephemeral);
+    let content_key = cipher(&wrapping_key(
+        shared.as_bytes(),
+        &ephemeral,
+        &PublicKey::from(key),
+    ))
+    .decrypt(Nonce::from_slice(&[0; NONCE_LEN]), wrapped)
+    .map_err(|_| anyhow!("Failed to unwrap content key"))?;
+    if content_key.len() != 32 {
+        return Err(anyhow!("Invalid content key"));
+    };
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&content_key);
+    Ok(out)
+}
+
+/// Ciphertext of an object along with the metadata headers to store it with.
+pub struct Sealed {
+    pub content: Vec<u8>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Sealed {
+    /// Sets the body and headers of an upload request, e.g. a PUT to `/<orbit>/s3/<key>`.
+    pub fn apply(self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        self.metadata
+            .into_iter()
+            .fold(request, |r, (k, v)| r.header(k, v))
+            .body(self.content)
+    }
+}
+
+/// Encrypts an object for a set of readers.
+pub fn seal(plaintext: &[u8], readers: &[PublicKey]) -> Result<Sealed> {
+    if readers.is_empty() {
+        return Err(anyhow!("An encrypted object needs at least one reader"));
+    };
+    let content_key = random::<32>()?;
+    let nonce = random::<NONCE_LEN>()?;
+    let ciphertext = cipher(&content_key)
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("Failed to encrypt content"))?;
+
+    let mut metadata = BTreeMap::new();
+    metadata.insert(SCHEME_HEADER.to_string(), SCHEME.to_string());
+    for reader in readers {
+        metadata.insert(key_header(reader), wrap(&content_key, reader)?);
+    }
+    Ok(Sealed {
+        content: [&nonce[..], &ciphertext].concat(),
+        metadata,
+    })
+}
+
+fn content_key(metadata: &BTreeMap<String, String>, key: &StaticSecret) -> Result<[u8; 32]> {
+    match metadata.get(SCHEME_HEADER).map(|s| s.as_str()) {
+        Some(SCHEME) => (),
+        Some(s) => return Err(anyhow!("Unsupported encryption scheme {}", s)),
+        None => return Err(anyhow!("Object is not encrypted")),
+    };
+    let wrapped = metadata
+        .get(&key_header(&PublicKey::from(key)))
+        .ok_or_else(|| anyhow!("Object is not shared with this reader"))?;
+    unwrap(wrapped, key)
+}
+
+/// Decrypts an object with a reader key it was sealed or shared for.
+pub fn open(
+    content: &[u8],
+    metadata: &BTreeMap<String, String>,
+    key: &StaticSecret,
+) -> Result<Vec<u8>> {
+    if content.len() < NONCE_LEN {
+        return Err(anyhow!("Encrypted content too short"));
+    };
+    let (nonce, ciphertext) = content.split_at(NONCE_LEN);
+    cipher(&content_key(metadata, key)?)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to decrypt content"))
+}
+
+/// Reads and decrypts the response to a GET of an object.
+pub async fn open_response(response: reqwest::Response, key: &StaticSecret) -> Result<Vec<u8>> {
+    let response = response.error_for_status()?;
+    let metadata: BTreeMap<String, String> = response
+        .headers()
+        .iter()
+        .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+        .collect();
+    open(&response.bytes().await?, &metadata, key)
+}
+
+/// Wraps the content key of an object for another reader, returning the header to add to the
+/// object's metadata. Requires a reader key the object is already shared with.
+pub fn share(
+    metadata: &BTreeMap<String, String>,
+    key: &StaticSecret,
+    reader: &PublicKey,
+) -> Result<(String, String)> {
+    Ok((
+        key_header(reader),
+        wrap(&content_key(metadata, key)?, reader)?,
+    ))
+}
+
+#[test]
+async fn seal_and_open() -> Result<()> {
+    let alice = generate_reader_key()?;
+    let bob = generate_reader_key()?;
+    let carol = generate_reader_key()?;
+    let mut sealed = seal(
+        b"private data",
+        &[PublicKey::from(&alice), PublicKey::from(&bob)],
+    )?;
+    assert!(!sealed
+        .content
+        .windows(b"private data".len())
+        .any(|w| w == b"private data"));
+
+    assert_eq!(
+        open(&sealed.content, &sealed.metadata, &alice)?,
+        b"private data"
+    );
+    assert_eq!(
+        open(&sealed.content, &sealed.metadata, &bob)?,
+        b"private data"
+    );
+    assert!(open(&sealed.content, &sealed.metadata, &carol).is_err());
+
+    let (header, wrapped) = share(&sealed.metadata, &bob, &PublicKey::from(&carol))?;
+    sealed.metadata.insert(header, wrapped);
+    assert_eq!(
+        open(&sealed.content, &sealed.metadata, &carol)?,
+        b"private data"
+    );
+
+    let last = sealed.content.len() - 1;
+    sealed.content[last] ^= 1;
+    assert!(open(&sealed.content, &sealed.metadata, &alice).is_err());
+    Ok(())
+}
@@ -0,0 +1,275 @@
+//! Public HTTP gateway serving the S3 objects of public orbits to browsers.
+//!
+//! Orbits are addressed by path, `/orbit/<orbit id>/<key>`, or by subdomain,
+//! `<orbit id>.<domain>/<key>`, which is rewritten to the path form before routing.
+
+use crate::cas::CidWrap;
+use crate::config;
+use crate::orbit::{load_orbit, Orbit};
+use crate::relay::RelayNode;
+use crate::s3::IpfsReadStream;
+use anyhow::Result;
+use libipld::cid::Cid;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{uri::Origin, ContentType, Header, Status},
+    response::{self, Responder, Response},
+    Data, Request, State,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Cursor,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub enum Page {
+    Object {
+        status: Status,
+        content_type: ContentType,
+        body: IpfsReadStream,
+    },
+    Listing(String),
+    // relative location of a directory requested without its trailing slash
+    Redirect(String),
+    NotFound,
+}
+
+impl<'r> Responder<'r, 'static> for Page {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Self::Object {
+                status,
+                content_type,
+                body,
+            } => Response::build()
+                .status(status)
+                .header(content_type)
+                .streamed_body(body)
+                .ok(),
+            Self::Listing(html) => Response::build()
+                .header(ContentType::HTML)
+                .sized_body(html.len(), Cursor::new(html))
+                .ok(),
+            Self::Redirect(location) => Response::build()
+                .status(Status::MovedPermanently)
+                .header(Header::new("Location", location))
+                .ok(),
+            Self::NotFound => Err(Status::NotFound),
+        }
+    }
+}
+
+// the type declared when the object was written, or guessed from its key
+fn content_type(metadata: &BTreeMap<String, String>, key: &str) -> ContentType {
+    metadata
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+        .and_then(|(_, v)| ContentType::parse_flexible(v))
+        .or_else(|| {
+            Path::new(key)
+                .extension()
+                .and_then(|e| e.to_str())
+                .and_then(ContentType::from_extension)
+        })
+        .unwrap_or(ContentType::Binary)
+}
+
+// only the content type of an object is served, its other metadata stays private
+async fn object(orbit: &Orbit, key: &str, status: Status) -> Result<Option<Page>> {
+    Ok(orbit
+        .read_key(key.as_bytes())
+        .await?
+        .map(|(md, body)| Page::Object {
+            status,
+            content_type: content_type(&md, key),
+            body,
+        }))
+}
+
+// names of the keys and subdirectories directly under a directory, `dir` is empty or ends with `/`
+fn children(keys: impl Iterator<Item = Vec<u8>>, dir: &str) -> BTreeSet<String> {
+    keys.filter_map(|k| String::from_utf8(k).ok())
+        .filter_map(|k| {
+            k.strip_prefix(dir).map(|rest| match rest.split_once('/') {
+                Some((sub, _)) => format!("{}/", sub),
+                None => rest.to_string(),
+            })
+        })
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn href(path: &str) -> String {
+    path.split('/')
+        .map(|s| urlencoding::encode(s).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn listing(dir: &str, children: &BTreeSet<String>) -> String {
+    let title = escape(&format!("Index of /{}", dir));
+    let items: String = children
+        .iter()
+        .map(|c| format!("<li><a href=\"{}\">{}</a></li>", href(c), escape(c)))
+        .collect();
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{0}</title></head><body><h1>{0}</h1><ul>{1}</ul></body></html>",
+        title, items
+    )
+}
+
+async fn serve(orbit: &Orbit, key: &str, path: &str, config: &config::Gateway) -> Result<Page> {
+    let slash = path.ends_with('/');
+    if !key.is_empty() && !slash {
+        if let Some(page) = object(orbit, key, Status::Ok).await? {
+            return Ok(page);
+        }
+    };
+
+    let dir = match key.is_empty() {
+        true => String::new(),
+        false => format!("{}/", key),
+    };
+    let children = children(orbit.entries().await?.into_keys(), &dir);
+    if !children.is_empty() {
+        // relative links in the directory resolve against its trailing slash
+        if !slash {
+            let last = path.rsplit('/').next().unwrap_or_default();
+            return Ok(Page::Redirect(format!("{}/", last)));
+        };
+        if let Some(page) = object(orbit, &[&dir, &config.index].concat(), Status::Ok).await? {
+            return Ok(page);
+        };
+        if config.listing {
+            return Ok(Page::Listing(listing(&dir, &children)));
+        };
+    };
+
+    Ok(object(orbit, &config.notfound, Status::NotFound)
+        .await?
+        .unwrap_or(Page::NotFound))
+}
+
+#[get("/orbit/<orbit_id>/<key..>", rank = 5)]
+pub async fn gateway(
+    orbit_id: CidWrap,
+    key: PathBuf,
+    uri: &Origin<'_>,
+    config: &State<config::Config>,
+    relay: &State<RelayNode>,
+) -> Result<Page, (Status, String)> {
+    let internal = |e: anyhow::Error| (Status::InternalServerError, e.to_string());
+    let orbit = match load_orbit(
+        orbit_id.0,
+        config.database.path.clone(),
+        (relay.id, relay.internal()),
+    )
+    .await
+    .map_err(internal)?
+    {
+        Some(o) => o.public().await.map_err(internal)?,
+        None => None,
+    };
+    // orbits which aren't public look the same as missing ones
+    let orbit = match orbit {
+        Some(o) => o,
+        None => return Ok(Page::NotFound),
+    };
+    let key = key
+        .to_str()
+        .ok_or_else(|| (Status::BadRequest, "Key parsing failed".to_string()))?;
+    let path = uri.to_string();
+    let path = path.split('?').next().unwrap_or_default();
+    serve(&orbit, key, path, &config.gateway)
+        .await
+        .map_err(internal)
+}
+
+// the orbit addressed by a gateway subdomain, hosts are case-insensitive so the orbit ID must be in
+// a base such as base32
+fn subdomain_orbit(host: &str, domain: &str) -> Option<Cid> {
+    let host = host.split(':').next()?;
+    let label = host.strip_suffix(domain)?.strip_suffix('.')?;
+    Cid::from_str(label).ok()
+}
+
+/// Routes requests for `<orbit id>.<domain>` to the gateway path of the orbit.
+pub struct Subdomains(pub String);
+
+#[rocket::async_trait]
+impl Fairing for Subdomains {
+    fn info(&self) -> Info {
+        Info {
+            name: "Gateway subdomains",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let oid = match req
+            .headers()
+            .get_one("Host")
+            .and_then(|h| subdomain_orbit(h, &self.0))
+        {
+            Some(o) => o,
+            None => return,
+        };
+        match Origin::parse_owned(format!("/orbit/{}{}", oid, req.uri())) {
+            Ok(uri) => req.set_uri(uri),
+            Err(e) => tracing::debug!("failed to rewrite gateway request: {}", e),
+        }
+    }
+}
+
+#[test]
+async fn addressing() {
+    let oid = "bafkreigh2akiscaildcqabsyg3dfr6chu3fgpregiymsck7e7aqa4s52zy";
+    assert_eq!(
+        subdomain_orbit(
+            &format!("{}.gateway.example.com:8000", oid),
+            "gateway.example.com"
+        ),
+        Some(oid.parse().unwrap())
+    );
+    assert_eq!(
+        subdomain_orbit("gateway.example.com", "gateway.example.com"),
+        None
+    );
+    assert_eq!(
+        subdomain_orbit("app.gateway.example.com", "gateway.example.com"),
+        None
+    );
+
+    let keys = ["index.html", "css/site.css", "css/print.css", "a b.txt"]
+        .iter()
+        .map(|k| k.as_bytes().to_vec());
+    let root = children(keys.clone(), "");
+    assert_eq!(
+        root.iter().cloned().collect::<Vec<_>>(),
+        vec!["a b.txt", "css/", "index.html"]
+    );
+    assert_eq!(children(keys, "css/").len(), 2);
+    assert!(listing("", &root).contains("<a href=\"a%20b.txt\">a b.txt</a>"));
+
+    let md = vec![("Content-Type".to_string(), "text/css".to_string())]
+        .into_iter()
+        .collect();
+    assert_eq!(content_type(&md, "site"), ContentType::CSS);
+    assert_eq!(
+        content_type(&Default::default(), "app.js"),
+        ContentType::JavaScript
+    );
+    assert_eq!(
+        content_type(&Default::default(), "blob"),
+        ContentType::Binary
+    );
+}
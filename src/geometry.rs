@@ -0,0 +1,563 @@
+//! Orbits assembled from other orbits hosted on the same node, as described in the Geometry
+//! section of ARCHITECTURE.md.
+
+use crate::{
+    auth::{Action, AuthorizationPolicy, AuthorizationToken},
+    orbit::{hosted_orbit_metadata, load_orbit, AuthTokens, Orbit, OrbitMetadata},
+    s3::{Entry, IpfsReadStream, Object, Store},
+    snapshots::Index,
+};
+use anyhow::Result;
+use async_recursion::async_recursion;
+use libipld::cid::Cid;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use ssi::did::DIDURL;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    /// Reads fall through to `base`, as of one of its snapshots when given. The orbit's own store
+    /// is the overlay, so writes and deletions never reach the base.
+    CopyOnWrite {
+        #[serde_as(as = "DisplayFromStr")]
+        base: Cid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        snapshot: Option<String>,
+    },
+    /// Read-only union of `members`, a key or CID is served by the first member holding it.
+    Compose {
+        #[serde_as(as = "Vec<DisplayFromStr>")]
+        members: Vec<Cid>,
+    },
+    /// Read-only view of `primary` whose objects carry the metadata of the object at the same
+    /// key in `sidecar`, the primary's own metadata taking precedence. The metadata is merged on
+    /// reads of this orbit rather than of `primary`, whose manifest doesn't know its sidecars.
+    Metadata {
+        #[serde_as(as = "DisplayFromStr")]
+        primary: Cid,
+        #[serde_as(as = "DisplayFromStr")]
+        sidecar: Cid,
+    },
+}
+
+// orbit parameter forms, `cow:<base>`, `cow:<base>@<snapshot>`, `compose:<member>,<member>..`
+// or `metadata:<primary>,<sidecar>`
+impl FromStr for Geometry {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("cow", base)) => {
+                let (base, snapshot) = match base.split_once('@') {
+                    Some((_, "")) => return Err(anyhow!("Empty snapshot label")),
+                    Some((base, label)) => (base, Some(label.to_string())),
+                    None => (base, None),
+                };
+                Ok(Self::CopyOnWrite {
+                    base: base.parse()?,
+                    snapshot,
+                })
+            }
+            Some(("compose", members)) if !members.is_empty() => Ok(Self::Compose {
+                members: members
+                    .split(',')
+                    .map(|m| Ok(m.parse()?))
+                    .collect::<Result<Vec<Cid>>>()?,
+            }),
+            Some(("metadata", orbits)) => match orbits.split_once(',') {
+                Some((primary, sidecar)) => Ok(Self::Metadata {
+                    primary: primary.parse()?,
+                    sidecar: sidecar.parse()?,
+                }),
+                None => Err(anyhow!(
+                    "Metadata geometry needs a primary and a sidecar orbit"
+                )),
+            },
+            _ => Err(anyhow!("Unknown orbit geometry {}", s)),
+        }
+    }
+}
+
+/// An S3 object found through an orbit's geometry, with the store holding its content.
+pub struct Resolved {
+    pub cid: Cid,
+    pub object: Object,
+    pub store: Store,
+}
+
+impl Resolved {
+    fn load(store: &Store, cid: Cid) -> Result<Self> {
+        Ok(Self {
+            cid,
+            object: store.ipfs.get(&cid)?.decode()?,
+            store: store.clone(),
+        })
+    }
+
+    pub fn read(self) -> Result<Option<(BTreeMap<String, String>, IpfsReadStream)>> {
+        self.store.read_object(self.object)
+    }
+}
+
+/// Who the reads of an orbit are served to, checked against each orbit of its geometry that the
+/// reads reach.
+#[derive(Clone)]
+pub enum Reader {
+    Token(Arc<AuthTokens>),
+    Public,
+}
+
+// orbits referred to by a geometry
+fn references(geometry: &Geometry) -> Vec<Cid> {
+    match geometry {
+        Geometry::CopyOnWrite { base, .. } => vec![*base],
+        Geometry::Compose { members } => members.clone(),
+        Geometry::Metadata { primary, sidecar } => vec![*primary, *sidecar],
+    }
+}
+
+// a read through a geometry can't reach an orbit on its path again
+fn check_path(path: &[Cid], id: &Cid) -> Result<()> {
+    match path.contains(id) {
+        true => Err(anyhow!("Orbit geometry refers back to {}", id)),
+        false => Ok(()),
+    }
+}
+
+/// Checks the geometry of a new orbit, whose creator must be able to read every orbit it refers
+/// to, and which can't be reached again through them.
+pub async fn check_geometry(md: &OrbitMetadata, creator: &DIDURL, path: &Path) -> Result<()> {
+    let geometry = match &md.geometry {
+        Some(g) => g,
+        None => return Ok(()),
+    };
+    let mut seen = HashSet::new();
+    let mut stack = references(geometry);
+    while let Some(id) = stack.pop() {
+        check_path(&[md.id], &id)?;
+        if !seen.insert(id) {
+            continue;
+        };
+        if let Some(g) = hosted_orbit_metadata(path, &id)
+            .await?
+            .and_then(|o| o.geometry)
+        {
+            stack.extend(references(&g));
+        };
+    }
+    for id in references(geometry) {
+        let orbit = hosted_orbit_metadata(path, &id)
+            .await?
+            .ok_or_else(|| anyhow!("Orbit {} is not hosted on this node", id))?;
+        if !orbit.public && !orbit.controllers.contains(creator) {
+            return Err(anyhow!("Creator can't read orbit {}", id));
+        };
+    }
+    Ok(())
+}
+
+impl Orbit {
+    // the orbits of a geometry must all be hosted on this node
+    async fn member(&self, id: &Cid, path: &[Cid]) -> Result<Orbit> {
+        check_path(path, id)?;
+        let mut member = load_orbit(*id, self.path(), self.relay())
+            .await?
+            .ok_or_else(|| anyhow!("Orbit {} is not hosted on this node", id))?;
+        member.reader = self.reader.clone();
+        Ok(member)
+    }
+
+    // whether the reader of this orbit may read a member, read-only members are authorized by
+    // their own members in turn
+    async fn readable(&self, member: &Orbit) -> bool {
+        match &self.reader {
+            None => true,
+            Some(Reader::Public) => member.public,
+            Some(Reader::Token(_)) if member.read_only() => true,
+            Some(Reader::Token(token)) => member.authorize(&**token).await.is_ok(),
+        }
+    }
+
+    // member of a geometry, if the reader may read it
+    async fn readable_member(&self, id: &Cid, path: &[Cid]) -> Result<Option<Orbit>> {
+        let member = self.member(id, path).await?;
+        Ok(match self.readable(&member).await {
+            true => Some(member),
+            false => None,
+        })
+    }
+
+    // the orbits whose geometry led to a read, ending with this one
+    fn path_to(&self, path: &[Cid]) -> Vec<Cid> {
+        [path, &[*self.id()]].concat()
+    }
+
+    fn snapshot_index(&self, label: &str) -> Result<Index> {
+        self.snapshots
+            .index(label, &self.service.store)?
+            .ok_or_else(|| anyhow!("Snapshot {} not found", label))
+    }
+
+    // readable orbits whose content a read-only orbit serves, in order of precedence
+    async fn members_in(&self, path: &[Cid]) -> Result<Option<Vec<Orbit>>> {
+        let ids = match &self.geometry {
+            Some(Geometry::Metadata { primary, .. }) => vec![*primary],
+            Some(Geometry::Compose { members }) => members.clone(),
+            _ => return Ok(None),
+        };
+        let path = self.path_to(path);
+        let mut orbits = vec![];
+        for id in &ids {
+            if let Some(member) = self.readable_member(id, &path).await? {
+                orbits.push(member);
+            }
+        }
+        Ok(Some(orbits))
+    }
+
+    pub(crate) async fn members(&self) -> Result<Option<Vec<Orbit>>> {
+        self.members_in(&[]).await
+    }
+
+    /// Finds the object at an S3 key.
+    pub async fn resolve(&self, key: &[u8]) -> Result<Option<Resolved>> {
+        self.resolve_in(key, &[]).await
+    }
+
+    #[async_recursion]
+    async fn resolve_in(&self, key: &[u8], path: &[Cid]) -> Result<Option<Resolved>> {
+        let inner = self.path_to(path);
+        if let Some(Geometry::Metadata { primary, sidecar }) = &self.geometry {
+            let primary = match self.readable_member(primary, &inner).await? {
+                Some(p) => p,
+                None => return Ok(None),
+            };
+            let mut found = match primary.resolve_in(key, &inner).await? {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            // the CID stays the primary's, only the returned metadata is merged, and only from a
+            // sidecar the reader may read
+            if let Some(sidecar) = self.readable_member(sidecar, &inner).await? {
+                if let Some(record) = sidecar.resolve_in(key, &inner).await? {
+                    for (k, v) in record.object.metadata {
+                        found.object.metadata.entry(k).or_insert(v);
+                    }
+                };
+            };
+            return Ok(Some(found));
+        };
+        if let Some(members) = self.members_in(path).await? {
+            for member in members {
+                if let Some(r) = member.resolve_in(key, &inner).await? {
+                    return Ok(Some(r));
+                }
+            }
+            return Ok(None);
+        };
+        let store = &self.service.store;
+        let (base, snapshot) = match (&self.geometry, store.entry(key)?) {
+            (_, Entry::Present(cid)) => return Ok(Some(Resolved::load(store, cid)?)),
+            (Some(Geometry::CopyOnWrite { base, snapshot }), Entry::Absent) => (base, snapshot),
+            _ => return Ok(None),
+        };
+        // reads only fall through to a base which the reader may read
+        let base = match self.readable_member(base, &inner).await? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        let found = match snapshot {
+            Some(label) => base
+                .snapshot_index(label)?
+                .get(key)
+                .map(|cid| Resolved::load(&base.service.store, *cid))
+                .transpose()?,
+            None => base.resolve_in(key, &inner).await?,
+        };
+        // base objects removed through this orbit are tombstoned in the overlay
+        Ok(match found {
+            Some(r) if !store.is_tombstoned(key, &r.cid)? => Some(r),
+            _ => None,
+        })
+    }
+
+    pub async fn read_key(
+        &self,
+        key: &[u8],
+    ) -> Result<Option<(BTreeMap<String, String>, IpfsReadStream)>> {
+        match self.resolve(key).await? {
+            Some(r) => r.read(),
+            None => Ok(None),
+        }
+    }
+
+    /// Keys and object CIDs of every S3 object visible in the orbit.
+    pub async fn entries(&self) -> Result<BTreeMap<Vec<u8>, Cid>> {
+        self.entries_in(&[]).await
+    }
+
+    #[async_recursion]
+    async fn entries_in(&self, path: &[Cid]) -> Result<BTreeMap<Vec<u8>, Cid>> {
+        let inner = self.path_to(path);
+        if let Some(members) = self.members_in(path).await? {
+            let mut entries = BTreeMap::new();
+            for member in members {
+                for (key, cid) in member.entries_in(&inner).await? {
+                    entries.entry(key).or_insert(cid);
+                }
+            }
+            return Ok(entries);
+        };
+        let store = &self.service.store;
+        let mut entries = store
+            .entries()
+            .map(|r| r.map(|(key, cid)| (key.to_vec(), cid)))
+            .collect::<Result<BTreeMap<Vec<u8>, Cid>>>()?;
+        let (base, snapshot) = match &self.geometry {
+            Some(Geometry::CopyOnWrite { base, snapshot }) => (base, snapshot),
+            _ => return Ok(entries),
+        };
+        let base = match self.readable_member(base, &inner).await? {
+            Some(b) => b,
+            None => return Ok(entries),
+        };
+        let base_entries = match snapshot {
+            Some(label) => base.snapshot_index(label)?.as_ref().clone(),
+            None => base.entries_in(&inner).await?,
+        };
+        for (key, cid) in base_entries {
+            if store.entry(&key)? == Entry::Absent && !store.is_tombstoned(&key, &cid)? {
+                entries.insert(key, cid);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_only(&self) -> bool {
+        matches!(
+            self.geometry,
+            Some(Geometry::Compose { .. }) | Some(Geometry::Metadata { .. })
+        )
+    }
+
+    pub fn check_writable(&self) -> Result<()> {
+        match self.read_only() {
+            true => Err(anyhow!("Orbit geometry is read-only")),
+            false => Ok(()),
+        }
+    }
+
+    /// The orbit as seen by the invoker of a token. Reads of a read-only geometry are authorized
+    /// by its members, and reads through a geometry only reach the orbits which authorize the
+    /// token.
+    pub async fn authorized(mut self, token: &AuthTokens) -> Result<Self> {
+        let read = match token.action() {
+            Action::Put(_) | Action::Del(_) | Action::Snapshot(_) if self.read_only() => {
+                return Err(anyhow!("Orbit geometry is read-only"))
+            }
+            Action::Get(_) | Action::List => true,
+            _ => false,
+        };
+        if read {
+            self.reader = Some(Reader::Token(Arc::new(token.clone())));
+        };
+        if let Action::Put(content) = token.action() {
+            self.write_scopes = content.clone();
+        };
+        if !read || !self.read_only() {
+            self.authorize(token).await?;
+        } else if self.members().await?.unwrap_or_default().is_empty() {
+            return Err(anyhow!("No member of the orbit authorizes the request"));
+        };
+        Ok(self)
+    }
+
+    /// The orbit as served without a token, if its content is public. Reads through its geometry
+    /// only reach public orbits, so a read-only geometry also needs public members.
+    pub async fn public(mut self) -> Result<Option<Self>> {
+        if !self.public {
+            return Ok(None);
+        };
+        self.reader = Some(Reader::Public);
+        if self.read_only() && self.members().await?.unwrap_or_default().is_empty() {
+            return Ok(None);
+        };
+        Ok(Some(self))
+    }
+
+    /// Removes the object at an S3 key, objects of a base orbit are hidden by a tombstone in the
+    /// overlay.
+    pub async fn remove_key(&self, key: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let store = &self.service.store;
+        let add: Vec<(&[u8], Cid)> = vec![];
+        if let Entry::Present(_) = store.entry(key)? {
+            return store.index(add, vec![(key, None)]);
+        };
+        match self.resolve(key).await? {
+            Some(r) => {
+                // peers merging the removal read the key from the object block
+                store.ipfs.insert(&r.store.ipfs.get(&r.cid)?)?;
+                store.index(add, vec![(key, Some((0, r.cid)))])
+            }
+            None => Err(anyhow!("Failed to find Object ID for key")),
+        }
+    }
+}
+
+#[test]
+async fn geometry_params() {
+    let base = "zCT5htkeBtA6Qu5YF4vPkQcfeqy3pY4m8zxGdUKUiPgtPEbY3rHy";
+    assert_eq!(
+        format!("cow:{}", base).parse::<Geometry>().unwrap(),
+        Geometry::CopyOnWrite {
+            base: base.parse().unwrap(),
+            snapshot: None
+        }
+    );
+    assert_eq!(
+        format!("cow:{}@v1", base).parse::<Geometry>().unwrap(),
+        Geometry::CopyOnWrite {
+            base: base.parse().unwrap(),
+            snapshot: Some("v1".into())
+        }
+    );
+    assert!(format!("cow:{}@", base).parse::<Geometry>().is_err());
+    assert_eq!(
+        format!("compose:{},{}", base, base)
+            .parse::<Geometry>()
+            .unwrap(),
+        Geometry::Compose {
+            members: vec![base.parse().unwrap(), base.parse().unwrap()]
+        }
+    );
+    assert!("compose:".parse::<Geometry>().is_err());
+    assert_eq!(
+        format!("metadata:{},{}", base, base)
+            .parse::<Geometry>()
+            .unwrap(),
+        Geometry::Metadata {
+            primary: base.parse().unwrap(),
+            sidecar: base.parse().unwrap()
+        }
+    );
+    assert!(format!("metadata:{}", base).parse::<Geometry>().is_err());
+    assert!(format!("mirror:{}", base).parse::<Geometry>().is_err());
+}
+
+#[cfg(test)]
+fn test_controller(key: &str) -> DIDURL {
+    DIDURL {
+        did: format!("did:key:{}", key),
+        fragment: Some(key.into()),
+        ..Default::default()
+    }
+}
+
+// manifest of an orbit controlled by `test_controller("z6MkAlice")`
+#[cfg(test)]
+fn test_manifest(id: &str, geometry: Option<&str>) -> OrbitMetadata {
+    OrbitMetadata {
+        id: id.parse().unwrap(),
+        controllers: vec![test_controller("z6MkAlice")],
+        read_delegators: vec![],
+        write_delegators: vec![],
+        hosts: Default::default(),
+        revocations: vec![],
+        credential_policies: vec![],
+        quota: None,
+        geometry: geometry.map(|g| g.parse().unwrap()),
+        encrypted: false,
+        public: false,
+    }
+}
+
+#[cfg(test)]
+fn host_manifest(path: &Path, md: &OrbitMetadata) {
+    use libipld::cid::multibase::Base;
+    let dir = path.join(md.id.to_string_of_base(Base::Base58Btc).unwrap());
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("metadata"), serde_json::to_vec(md).unwrap()).unwrap();
+}
+
+#[test]
+async fn geometry_access() -> Result<()> {
+    let (alice, bob) = (test_controller("z6MkAlice"), test_controller("z6MkBob"));
+    let tmp = tempdir::TempDir::new("geometry")?;
+    let (base, overlay) = (
+        "zCT5htkeBtA6Qu5YF4vPkQcfeqy3pY4m8zxGdUKUiPgtPEbY3rHy",
+        "zCT5htkeCSu7WefuBKYUidQJkRgEvEGZQrFVqYS6ZJVM6zwLCRcF",
+    );
+    let cow = test_manifest(overlay, Some(&format!("cow:{}", base)));
+
+    // referenced orbits must be hosted, and readable by the creator
+    assert!(check_geometry(&cow, &alice, tmp.path()).await.is_err());
+    let mut base_md = test_manifest(base, None);
+    host_manifest(tmp.path(), &base_md);
+    assert!(check_geometry(&cow, &alice, tmp.path()).await.is_ok());
+    assert!(check_geometry(&cow, &bob, tmp.path()).await.is_err());
+    base_md.public = true;
+    host_manifest(tmp.path(), &base_md);
+    assert!(check_geometry(&cow, &bob, tmp.path()).await.is_ok());
+
+    // the base refers back to the new orbit through its own geometry
+    host_manifest(
+        tmp.path(),
+        &test_manifest(base, Some(&format!("cow:{}", overlay))),
+    );
+    assert!(check_geometry(&cow, &alice, tmp.path()).await.is_err());
+
+    assert!(check_path(&[overlay.parse()?], &base.parse()?).is_ok());
+    assert!(check_path(&[overlay.parse()?, base.parse()?], &overlay.parse()?).is_err());
+    Ok(())
+}
+
+#[test]
+async fn compose_cycles() -> Result<()> {
+    let alice = test_controller("z6MkAlice");
+    let tmp = tempdir::TempDir::new("compose")?;
+    let (a, b, c) = (
+        "zCT5htkeBtA6Qu5YF4vPkQcfeqy3pY4m8zxGdUKUiPgtPEbY3rHy",
+        "zCT5htkeCSu7WefuBKYUidQJkRgEvEGZQrFVqYS6ZJVM6zwLCRcF",
+        "zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg",
+    );
+
+    // a composed orbit listing itself
+    let own = test_manifest(a, Some(&format!("compose:{},{}", b, a)));
+    host_manifest(tmp.path(), &test_manifest(b, None));
+    assert!(check_geometry(&own, &alice, tmp.path()).await.is_err());
+
+    // nested through another composed orbit, and through a copy-on-write
+    let nested = test_manifest(a, Some(&format!("compose:{}", b)));
+    host_manifest(
+        tmp.path(),
+        &test_manifest(b, Some(&format!("compose:{},{}", c, a))),
+    );
+    host_manifest(tmp.path(), &test_manifest(c, None));
+    assert!(check_geometry(&nested, &alice, tmp.path()).await.is_err());
+    host_manifest(tmp.path(), &test_manifest(b, Some(&format!("cow:{}", c))));
+    host_manifest(
+        tmp.path(),
+        &test_manifest(c, Some(&format!("compose:{}", a))),
+    );
+    assert!(check_geometry(&nested, &alice, tmp.path()).await.is_err());
+
+    // the same member twice is not a cycle
+    host_manifest(tmp.path(), &test_manifest(c, None));
+    let twice = test_manifest(a, Some(&format!("compose:{},{}", b, c)));
+    host_manifest(tmp.path(), &test_manifest(b, Some(&format!("cow:{}", c))));
+    assert!(check_geometry(&twice, &alice, tmp.path()).await.is_ok());
+
+    // reads through members can't reach an orbit of their path again
+    let path = [a.parse()?, b.parse()?];
+    assert!(check_path(&path, &a.parse()?).is_err());
+    assert!(check_path(&path, &c.parse()?).is_ok());
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use crate::config;
+use anyhow::Result;
+use ipfs_embed::{generate_keypair, Keypair, PeerId, ToLibp2p};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::{convert::TryInto, path::Path};
+
+/// Host keys generated for orbits which haven't been created yet.
+///
+/// Pending keys are persisted next to the orbits so they survive restarts, and are discarded once
+/// they expire, are revoked or are used to create an orbit.
+pub struct HostKeys {
+    db: sled::Db,
+    lifetime: i64,
+    limit: usize,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PendingKey {
+    #[serde_as(as = "DisplayFromStr")]
+    pub id: PeerId,
+    // unix timestamp after which the key can't be used anymore
+    pub expires: i64,
+}
+
+// values are the expiry timestamp followed by the keypair
+fn decode(value: &[u8]) -> Result<(i64, Keypair)> {
+    if value.len() < 8 {
+        return Err(anyhow!("Invalid pending host key"));
+    };
+    let (expires, key) = value.split_at(8);
+    Ok((
+        i64::from_be_bytes(expires.try_into()?),
+        Keypair::from_bytes(key)?,
+    ))
+}
+
+impl HostKeys {
+    pub fn open(path: &Path, config: &config::HostKeys) -> Result<Self> {
+        Ok(Self {
+            db: sled::open(path.join("host_keys"))?,
+            lifetime: config.lifetime as i64,
+            limit: config.limit,
+        })
+    }
+
+    fn prune(&self, now: i64) -> Result<()> {
+        for entry in self.db.iter() {
+            let (id, value) = entry?;
+            if decode(&value)
+                .map(|(expires, _)| expires < now)
+                .unwrap_or(true)
+            {
+                self.db.remove(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn generate(&self, now: i64) -> Result<PendingKey> {
+        self.prune(now)?;
+        if self.db.len() >= self.limit {
+            return Err(anyhow!("Too many pending host keys"));
+        };
+        let keypair = generate_keypair();
+        let key = PendingKey {
+            id: keypair.to_peer_id(),
+            expires: now + self.lifetime,
+        };
+        self.db.insert(
+            key.id.to_bytes(),
+            [&key.expires.to_be_bytes()[..], &keypair.to_bytes()].concat(),
+        )?;
+        self.db.flush()?;
+        Ok(key)
+    }
+
+    pub fn list(&self, now: i64) -> Result<Vec<PendingKey>> {
+        self.prune(now)?;
+        self.db
+            .iter()
+            .map(|entry| {
+                let (id, value) = entry?;
+                Ok(PendingKey {
+                    id: PeerId::from_bytes(&id)?,
+                    expires: decode(&value)?.0,
+                })
+            })
+            .collect()
+    }
+
+    /// Discards a pending key, returning whether it existed.
+    pub fn revoke(&self, id: &PeerId) -> Result<bool> {
+        let removed = self.db.remove(id.to_bytes())?.is_some();
+        self.db.flush()?;
+        Ok(removed)
+    }
+
+    /// Removes and returns the key of a peer, unless it has expired.
+    pub fn take(&self, id: &PeerId, now: i64) -> Result<Option<Keypair>> {
+        let value = match self.db.remove(id.to_bytes())? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        self.db.flush()?;
+        let (expires, keypair) = decode(&value)?;
+        Ok(if expires < now { None } else { Some(keypair) })
+    }
+}
+
+#[test]
+async fn lifecycle() -> Result<()> {
+    let tmp = tempdir::TempDir::new("host_keys")?;
+    let config = config::HostKeys {
+        lifetime: 60,
+        limit: 2,
+    };
+    let now = 1_600_000_000;
+    let keys = HostKeys::open(tmp.path(), &config)?;
+    let first = keys.generate(now)?;
+    let second = keys.generate(now)?;
+    assert!(keys.generate(now).is_err());
+    assert_eq!(keys.list(now)?.len(), 2);
+
+    assert!(keys.revoke(&first.id)?);
+    assert!(!keys.revoke(&first.id)?);
+    assert!(keys.take(&first.id, now)?.is_none());
+    drop(keys);
+
+    // pending keys survive a restart
+    let keys = HostKeys::open(tmp.path(), &config)?;
+    assert_eq!(keys.list(now)?, vec![second.clone()]);
+    assert_eq!(
+        keys.take(&second.id, now)?.map(|kp| kp.to_peer_id()),
+        Some(second.id)
+    );
+    assert!(keys.list(now)?.is_empty());
+
+    let key = keys.generate(now)?;
+    assert!(keys.take(&key.id, now + 61)?.is_none());
+    let key = keys.generate(now)?;
+    assert_eq!(keys.list(now + 61)?, vec![]);
+    assert!(keys.take(&key.id, now)?.is_none());
+    Ok(())
+}
@@ -6,31 +6,61 @@ extern crate anyhow;
 extern crate tokio;
 
 use anyhow::Result;
-use rocket::{fairing::AdHoc, figment::Figment, http::Header, tokio::fs, Build, Rocket};
+use libipld::{cid::Cid, multibase::Base};
+use rocket::{
+    fairing::AdHoc,
+    figment::Figment,
+    futures::StreamExt,
+    http::Header,
+    tokio::{
+        fs,
+        io::{AsyncWriteExt, BufReader},
+    },
+    Build, Rocket,
+};
 
 pub mod allow_list;
+pub mod audit;
 pub mod auth;
+pub mod car;
 pub mod cas;
 pub mod codec;
 pub mod config;
+pub mod encryption;
+pub mod gateway;
+pub mod geometry;
+pub mod host_keys;
 pub mod ipfs;
+pub mod metrics;
 pub mod orbit;
+pub mod peers;
 pub mod relay;
+pub mod resolver;
+pub mod revocations;
 pub mod routes;
 pub mod s3;
 pub mod s3_routes;
+pub mod snapshots;
 pub mod tz;
 pub mod tz_orbit;
+pub mod ucan;
+pub mod vp;
 pub mod zcap;
 
-use ipfs_embed::{generate_keypair, Keypair, PeerId, ToLibp2p};
+use allow_list::AllowList;
+use audit::Audit;
+use host_keys::HostKeys;
+use ipfs_embed::{generate_keypair, Keypair, Multiaddr, PeerId, ToLibp2p};
+use metrics::Metrics;
 use relay::RelayNode;
 use routes::{
-    batch_put_content, cors, delete_content, get_content, get_content_no_auth, list_content,
-    list_content_no_auth, open_host_key, open_orbit_allowlist, open_orbit_authz, put_content,
-    relay_addr,
+    audit_log, batch_put_content, cors, create_snapshot, delete_content, delete_orbit,
+    export_orbit, get_content, import_orbit, list_content, list_host_keys, list_peers,
+    open_host_key, open_orbit_allowlist, open_orbit_authz, orbit_peers, put_content, relay_addr,
+    revoke_delegations, revoke_host_key, rotate_host_key, update_manifest,
 };
-use std::{collections::HashMap, sync::RwLock};
+use std::path::Path;
+use tz::NonceCache;
 
 pub fn tracing_try_init() {
     tracing_subscriber::fmt()
@@ -39,6 +69,58 @@ pub fn tracing_try_init() {
         .ok();
 }
 
+async fn node_keypair(kepler_config: &config::Config) -> Result<Keypair> {
+    Ok(
+        if let Ok(bytes) = fs::read(kepler_config.database.path.join("kp")).await {
+            Keypair::from_bytes(&bytes)?
+        } else {
+            let kp = generate_keypair();
+            fs::write(kepler_config.database.path.join("kp"), kp.to_bytes()).await?;
+            kp
+        },
+    )
+}
+
+// orbits are loaded with the relay's addresses, but the relay isn't started while the node is
+// stopped, so its ports stay free
+async fn offline_relay(
+    kepler_config: &config::Config,
+) -> Result<((PeerId, Multiaddr), Vec<Multiaddr>)> {
+    let id = node_keypair(kepler_config).await?.to_peer_id();
+    let (internal, advertised) = relay::offline_addrs(&kepler_config.relay, id)?;
+    Ok(((id, internal), advertised))
+}
+
+/// Writes an orbit as a CAR archive to a file, for backups while the node is stopped.
+pub async fn export(config: &Figment, oid: Cid, out: &Path) -> Result<()> {
+    let kepler_config = config.extract::<config::Config>()?;
+    let (relay, _) = offline_relay(&kepler_config).await?;
+    let orbit = orbit::load_orbit(oid, kepler_config.database.path.clone(), relay)
+        .await?
+        .ok_or_else(|| anyhow!("No Orbit found"))?;
+    let mut file = fs::File::create(out).await?;
+    let mut archive = Box::pin(car::export(&orbit).await?);
+    while let Some(chunk) = archive.next().await {
+        file.write_all(&chunk).await?;
+    }
+    Ok(file.flush().await?)
+}
+
+/// Creates an orbit from a CAR archive file, returning its ID.
+pub async fn import(config: &Figment, archive: &Path) -> Result<String> {
+    let kepler_config = config.extract::<config::Config>()?;
+    let (relay, advertised) = offline_relay(&kepler_config).await?;
+    let orbit = car::import(
+        BufReader::new(fs::File::open(archive).await?),
+        kepler_config.database.path.clone(),
+        relay,
+        &advertised,
+        &HostKeys::open(&kepler_config.database.path, &kepler_config.keys)?,
+    )
+    .await?;
+    Ok(orbit.id().to_string_of_base(Base::Base58Btc)?)
+}
+
 pub async fn app(config: &Figment) -> Result<Rocket<Build>> {
     let kepler_config = config.extract::<config::Config>()?;
 
@@ -50,17 +132,34 @@ pub async fn app(config: &Figment) -> Result<Rocket<Build>> {
         ));
     }
 
-    let kp: Keypair = if let Ok(bytes) = fs::read(kepler_config.database.path.join("kp")).await {
-        Keypair::from_bytes(&bytes)?
-    } else {
-        let kp = generate_keypair();
-        fs::write(kepler_config.database.path.join("kp"), kp.to_bytes()).await?;
-        kp
+    let kp = node_keypair(&kepler_config).await?;
+    if kepler_config.auth.domain.is_none() {
+        tracing::warn!(
+            "auth.domain is not set, tokens addressed to any node are accepted by this one"
+        );
     };
+    orbit::set_control_auth(&kepler_config.auth)?;
+    orbit::remove_tombstones(&kepler_config.database.path).await?;
 
-    let relay_node = RelayNode::new(kepler_config.relay.port, kp.to_keypair())?;
+    let relay_node = RelayNode::new(
+        &kepler_config.relay,
+        kepler_config.database.path.clone(),
+        kp.to_keypair(),
+    )?;
+    let host_keys = HostKeys::open(&kepler_config.database.path, &kepler_config.keys)?;
+    let allowlist = kepler_config
+        .orbits
+        .allowlist
+        .clone()
+        .map(|service| AllowList::new(service, kp.to_keypair()));
 
+    // reads of public orbits are let through by the guards of these routes
     let mut routes = routes![
+        get_content,
+        list_content,
+        s3_routes::get_content,
+        s3_routes::list_content,
+        s3_routes::get_snapshot_content,
         put_content,
         batch_put_content,
         delete_content,
@@ -70,30 +169,32 @@ pub async fn app(config: &Figment) -> Result<Rocket<Build>> {
         s3_routes::put_content,
         s3_routes::delete_content,
         relay_addr,
-        open_host_key
+        open_host_key,
+        list_host_keys,
+        revoke_host_key,
+        rotate_host_key,
+        list_peers,
+        orbit_peers,
+        metrics::metrics,
+        resolver::resolve_uri,
+        revoke_delegations,
+        delete_orbit,
+        export_orbit,
+        import_orbit,
+        create_snapshot,
+        update_manifest,
+        audit_log
     ];
 
-    if kepler_config.orbits.public {
-        let mut no_auth = routes![
-            get_content_no_auth,
-            list_content_no_auth,
-            s3_routes::get_content_no_auth,
-            s3_routes::list_content_no_auth,
-        ];
-        routes.append(&mut no_auth);
-    } else {
-        let mut auth = routes![
-            get_content,
-            list_content,
-            s3_routes::get_content,
-            s3_routes::list_content,
-        ];
-        routes.append(&mut auth);
-    }
+    if kepler_config.gateway.enabled {
+        routes.append(&mut routes![gateway::gateway]);
+    };
 
-    Ok(rocket::custom(config)
+    let rocket = rocket::custom(config)
         .mount("/", routes)
         .attach(AdHoc::config::<config::Config>())
+        .attach(Metrics)
+        .attach(Audit)
         .attach(AdHoc::on_response("CORS", |_, resp| {
             Box::pin(async move {
                 resp.set_header(Header::new("Access-Control-Allow-Origin", "*"));
@@ -106,7 +207,19 @@ pub async fn app(config: &Figment) -> Result<Rocket<Build>> {
             })
         }))
         .manage(relay_node)
-        .manage(RwLock::new(HashMap::<PeerId, Keypair>::new())))
+        .manage(NonceCache::default())
+        .manage(host_keys);
+
+    // subdomains are routed to the gateway by rewriting their requests
+    let rocket = match (kepler_config.gateway.enabled, kepler_config.gateway.domain) {
+        (true, Some(domain)) => rocket.attach(gateway::Subdomains(domain)),
+        _ => rocket,
+    };
+
+    Ok(match allowlist {
+        Some(list) => rocket.manage(list),
+        None => rocket,
+    })
 }
 
 #[test]
@@ -155,3 +268,56 @@ Content-Type: application/json
 
     assert!(res.status().class().is_success());
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stale_tokens() -> Result<()> {
+    use rocket::{figment::providers::Serialized, http::Status, local::asynchronous::Client};
+    let tmp = tempdir::TempDir::new("guards")?;
+    let client = |name: &str, port: u16, auth: config::Auth| {
+        let path = tmp.path().join(name);
+        async move {
+            std::fs::create_dir(&path)?;
+            let mut kepler_config = config::Config::default();
+            kepler_config.database.path = path;
+            kepler_config.relay.port = port;
+            kepler_config.auth = auth;
+            let figment = Figment::from(rocket::Config::default())
+                .merge(Serialized::defaults(kepler_config));
+            Ok(Client::tracked(app(&figment).await?).await?) as Result<Client>
+        }
+    };
+    // signed for domain `test` in 2021, for content of an orbit which isn't hosted
+    const TOKEN: &str = "Tezos Signed Message: test 2021-08-16T12:00:52.699Z edpkuthnQ7YdexSxGEHYSbrweH31Zd75roc7W42Lgt8LJM8PX4sX6m tz1WWXeGFgtARRLPPzT2qcpeiQZ8oQb6rBZd z3v8BBKAxmb5DPsoCsaucZZ26FzPSbLWDAGtpHSiKjA4AJLQ3my GET z3v8BBKAGbGkuFU8TQq3J7k9XDs9udtMCic4KMS6HBxHczS1Tyv edsigtigutx55QVaLT3iC89yQnF5bnRecztiYbs1LtaMN84KXWtTxtRGBpkiz9eVZG6MqwHp1K7KGAhjHSyfJRQMs1EAyYBNTYZ";
+    async fn get(client: &Client) -> Status {
+        client
+            .get("/z3v8BBKAxmb5DPsoCsaucZZ26FzPSbLWDAGtpHSiKjA4AJLQ3my/z3v8BBKAGbGkuFU8TQq3J7k9XDs9udtMCic4KMS6HBxHczS1Tyv")
+            .header(Header::new("Authorization", TOKEN))
+            .dispatch()
+            .await
+            .status()
+    }
+    let years = 10 * 365 * 24 * 3600;
+
+    // expired
+    let strict = client("strict", 10003, Default::default()).await?;
+    assert_eq!(get(&strict).await, Status::Unauthorized);
+
+    // addressed to another node
+    let other = config::Auth {
+        domain: Some("kepler.example.com".into()),
+        lifetime: years,
+        ..Default::default()
+    };
+    let other = client("other", 10004, other).await?;
+    assert_eq!(get(&other).await, Status::Unauthorized);
+
+    // fresh and addressed to this node, so the guard goes on to look for the orbit
+    let lenient = config::Auth {
+        domain: Some("test".into()),
+        lifetime: years,
+        ..Default::default()
+    };
+    let lenient = client("lenient", 10005, lenient).await?;
+    assert_eq!(get(&lenient).await, Status::NotFound);
+    Ok(())
+}
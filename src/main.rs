@@ -1,5 +1,9 @@
-use kepler::{app, config, tracing_try_init};
+use anyhow::anyhow;
+use kepler::{app, config, export, import, tracing_try_init};
 use rocket::figment::providers::{Format, Serialized, Toml, Env};
+use std::path::Path;
+
+const USAGE: &str = "usage: kepler [export <orbit-id> <archive.car> | import <archive.car>]";
 
 #[rocket::main]
 async fn main() {
@@ -11,5 +15,29 @@ async fn main() {
         .merge(Env::prefixed("KEPLER_").split("_").global())
         .merge(Env::prefixed("ROCKET_").global()); // That's just for easy access to ROCKET_LOG_LEVEL
 
-    app(&config).await.unwrap().launch().await.unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        // kepler export <orbit-id> <archive.car>
+        ["export", orbit, out] => match orbit.parse() {
+            Ok(oid) => export(&config, oid, Path::new(out)).await,
+            Err(e) => Err(anyhow!("Invalid orbit ID {}: {}", orbit, e)),
+        },
+        // kepler import <archive.car>
+        ["import", archive] => import(&config, Path::new(archive))
+            .await
+            .map(|id| println!("{}", id)),
+        [] => match app(&config).await {
+            Ok(rocket) => rocket.launch().await.map_err(|e| anyhow!(e.to_string())),
+            Err(e) => Err(e),
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
 }
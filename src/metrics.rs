@@ -0,0 +1,252 @@
+//! Prometheus metrics of the node, served to admins at `/metrics`.
+
+use crate::auth::AdminAuth;
+use crate::config;
+use crate::orbit::cache_stats;
+use crate::relay::RelayNode;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{
+    core::Collector, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Status,
+    Data, Request, Response, Rocket, State,
+};
+use std::{
+    sync::atomic::Ordering,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "kepler_http_requests_total",
+        "HTTP requests by route and status",
+        &["route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "kepler_http_request_duration_seconds",
+        "HTTP request durations by route",
+        &["route"]
+    )
+    .unwrap();
+    static ref AUTH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "kepler_authorization_failures_total",
+        "Requests refused as unauthorized or forbidden, by HTTP method",
+        &["method"]
+    )
+    .unwrap();
+    static ref HTTP_BYTES: IntCounterVec = register_int_counter_vec!(
+        "kepler_http_bytes_total",
+        "Bytes of HTTP bodies with a known size, in or out",
+        &["direction"]
+    )
+    .unwrap();
+    static ref ORBIT_CACHE: IntCounterVec = register_int_counter_vec!(
+        "kepler_orbit_cache_total",
+        "Lookups of loaded orbits, by hit or miss",
+        &["result"]
+    )
+    .unwrap();
+    static ref DELTA_MERGES: IntCounterVec = register_int_counter_vec!(
+        "kepler_delta_merges_total",
+        "Heads received from peers merged into a store, by success or failure",
+        &["result"]
+    )
+    .unwrap();
+    static ref GOSSIP_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "kepler_gossip_messages_total",
+        "Gossip messages received, by kind",
+        &["kind"]
+    )
+    .unwrap();
+    static ref RELAY_CONNECTIONS: IntGauge = register_int_gauge!(
+        "kepler_relay_connections",
+        "Connections currently open to the relay"
+    )
+    .unwrap();
+    static ref RELAY_CIRCUITS: IntGauge = register_int_gauge!(
+        "kepler_relay_circuits",
+        "Circuits currently relayed for remote peers"
+    )
+    .unwrap();
+    static ref RELAY_DENIED: IntCounter = register_int_counter!(
+        "kepler_relay_denied_total",
+        "Relayed circuits refused or closed because of the limits"
+    )
+    .unwrap();
+    static ref RELAY_BYTES: IntCounter = register_int_counter!(
+        "kepler_relay_bytes_total",
+        "Bytes carried by circuits relayed for remote peers"
+    )
+    .unwrap();
+    static ref STORAGE: IntGauge = register_int_gauge!(
+        "kepler_storage_bytes",
+        "Bytes of content stored by the orbits loaded since the node started"
+    )
+    .unwrap();
+    // not registered, as its labels list the hosted orbits, and only exported when configured
+    static ref STORE_STORAGE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "kepler_store_storage_bytes",
+            "Bytes of content stored by each store of the loaded orbits"
+        ),
+        &["store"]
+    )
+    .unwrap();
+    static ref START_TIME: IntGauge = register_int_gauge!(
+        "kepler_start_time_seconds",
+        "Unix timestamp at which the node started"
+    )
+    .unwrap();
+}
+
+pub fn delta_merge(ok: bool) {
+    DELTA_MERGES
+        .with_label_values(&[if ok { "success" } else { "failure" }])
+        .inc();
+}
+
+pub fn gossip_message(kind: &str) {
+    GOSSIP_MESSAGES.with_label_values(&[kind]).inc();
+}
+
+/// Records the content bytes of a store, as they change.
+pub fn storage(store: &str, bytes: u64) {
+    STORE_STORAGE.with_label_values(&[store]).set(bytes as i64);
+}
+
+/// Drops the stores of a deleted orbit from the storage gauges.
+pub fn forget_storage(orbit: &str) {
+    for store in [
+        orbit.to_string(),
+        format!("{}/revocations", orbit),
+        format!("{}/snapshots", orbit),
+    ] {
+        let _ = STORE_STORAGE.remove_label_values(&[&store]);
+    }
+}
+
+// brings a counter up to a total kept elsewhere
+fn advance(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+/// Records requests, their durations and body sizes.
+pub struct Metrics;
+
+#[rocket::async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Metrics",
+            kind: Kind::Liftoff | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_liftoff(&self, _: &Rocket<rocket::Orbit>) {
+        START_TIME.set(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+        );
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(Instant::now);
+        if let Some(size) = req
+            .headers()
+            .get_one("Content-Length")
+            .and_then(|l| l.parse().ok())
+        {
+            HTTP_BYTES.with_label_values(&["in"]).inc_by(size);
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        // unmatched requests are grouped together so paths can't grow the label set
+        let route = req
+            .route()
+            .map(|r| r.uri.to_string())
+            .unwrap_or_else(|| "unmatched".into());
+        let status = res.status();
+        HTTP_REQUESTS
+            .with_label_values(&[&route, &status.code.to_string()])
+            .inc();
+        HTTP_DURATION
+            .with_label_values(&[&route])
+            .observe(req.local_cache(Instant::now).elapsed().as_secs_f64());
+        if status == Status::Unauthorized || status == Status::Forbidden {
+            AUTH_FAILURES
+                .with_label_values(&[req.method().as_str()])
+                .inc();
+        };
+        if let Some(size) = res.body().preset_size() {
+            HTTP_BYTES.with_label_values(&["out"]).inc_by(size as u64);
+        }
+    }
+}
+
+async fn gather(config: &config::Metrics, relay: &RelayNode) -> Result<String> {
+    let (hits, misses) = cache_stats().await;
+    advance(&ORBIT_CACHE.with_label_values(&["hit"]), hits);
+    advance(&ORBIT_CACHE.with_label_values(&["miss"]), misses);
+
+    let stats = relay.stats();
+    RELAY_CONNECTIONS.set(stats.connections.load(Ordering::Relaxed) as i64);
+    RELAY_CIRCUITS.set(stats.circuits.load(Ordering::Relaxed) as i64);
+    advance(&RELAY_DENIED, stats.denied.load(Ordering::Relaxed));
+    advance(&RELAY_BYTES, stats.bytes.load(Ordering::Relaxed));
+
+    let stores = STORE_STORAGE.collect();
+    STORAGE.set(
+        stores
+            .iter()
+            .flat_map(|f| f.get_metric())
+            .map(|m| m.get_gauge().get_value() as i64)
+            .sum(),
+    );
+
+    let mut families = prometheus::gather();
+    if config.orbits {
+        families.extend(stores);
+    };
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&families, &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[get("/metrics")]
+pub async fn metrics(
+    _admin: AdminAuth,
+    config: &State<config::Config>,
+    relay: &State<RelayNode>,
+) -> Result<String, (Status, String)> {
+    gather(&config.metrics, relay)
+        .await
+        .map_err(|e| (Status::InternalServerError, e.to_string()))
+}
+
+#[test]
+async fn counters() {
+    let merges = DELTA_MERGES.with_label_values(&["failure"]).get();
+    delta_merge(false);
+    assert_eq!(
+        DELTA_MERGES.with_label_values(&["failure"]).get(),
+        merges + 1
+    );
+
+    advance(&RELAY_BYTES, 10);
+    advance(&RELAY_BYTES, 25);
+    assert_eq!(RELAY_BYTES.get(), 25);
+
+    storage("counters", 10);
+    assert_eq!(STORE_STORAGE.with_label_values(&["counters"]).get(), 10);
+    forget_storage("counters");
+    assert!(STORE_STORAGE.remove_label_values(&["counters"]).is_err());
+}
@@ -1,16 +1,30 @@
 use crate::{
+    allow_list::{AllowListEntry, OrbitAllowList},
+    audit::AuditLog,
     auth::{Action, AuthorizationPolicy, AuthorizationToken},
     cas::ContentAddressedStorage,
     codec::SupportedCodecs,
-    config::ExternalApis,
+    config::{self, CreationPolicy, ExternalApis},
+    geometry::{Geometry, Reader},
+    host_keys::HostKeys,
     ipfs::Ipfs,
+    metrics,
+    peers::Activity,
+    revocations::Revocations,
     s3::{Service, Store},
-    tz::TezosAuthorizationString,
+    snapshots::Snapshots,
+    tz::{NonceCache, TezosAuthorizationString},
     tz_orbit::params_to_tz_orbit,
-    zcap::ZCAPTokens,
+    ucan::UCANTokens,
+    vp::{CredentialPolicy, PresentationTokens},
+    zcap::{self, KeplerDelegation, KeplerInvocation, ZCAPTokens},
 };
 use anyhow::{anyhow, Result};
-use ipfs_embed::{generate_keypair, multiaddr::multiaddr, Config, Keypair, Multiaddr, PeerId};
+use ipfs_embed::{
+    generate_keypair,
+    multiaddr::{multiaddr, Protocol},
+    Config, GossipEvent, Keypair, Multiaddr, PeerId, ToLibp2p,
+};
 use libipld::cid::{
     multibase::Base,
     multihash::{Code, MultihashDigest},
@@ -23,16 +37,19 @@ use rocket::{
     tokio::{fs, task::JoinHandle},
 };
 
-use cached::proc_macro::cached;
+use cached::{proc_macro::cached, Cached};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use ssi::did::DIDURL;
 use std::{
-    collections::HashMap as Map,
+    collections::{HashMap as Map, HashSet},
     convert::TryFrom,
     ops::Deref,
-    path::PathBuf,
-    sync::{Arc, RwLock},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, Weak},
+    time::Duration,
 };
 
 #[serde_as]
@@ -47,8 +64,22 @@ pub struct OrbitMetadata {
     #[serde(default)]
     #[serde_as(as = "Map<DisplayFromStr, _>")]
     pub hosts: Map<PeerId, Vec<Multiaddr>>,
-    // TODO placeholder type
+    // IDs of delegations revoked by the manifest itself, published revocations live in the orbit
     pub revocations: Vec<String>,
+    #[serde(default)]
+    pub credential_policies: Vec<CredentialPolicy>,
+    // maximum storage in bytes, set by the allowlist which provisioned the orbit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+    // how the orbit is assembled from other orbits, set by the `geometry` parameter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geometry: Option<Geometry>,
+    // objects are encrypted by clients, see `encryption`, and hosts refuse plaintext writes
+    #[serde(default)]
+    pub encrypted: bool,
+    // content can be read without a token, set by the `public` parameter or a manifest update
+    #[serde(default)]
+    pub public: bool,
 }
 
 impl OrbitMetadata {
@@ -64,6 +95,28 @@ impl OrbitMetadata {
         &self.controllers
     }
 
+    // an allowlist entry overrides the implicit controllers and extends the rest
+    pub fn apply(&mut self, entry: AllowListEntry) {
+        if !entry.controllers.is_empty() {
+            self.controllers = entry.controllers;
+        };
+        self.read_delegators.extend(entry.read_delegators);
+        self.write_delegators.extend(entry.write_delegators);
+        self.hosts.extend(entry.hosts);
+        self.quota = entry.quota.or(self.quota);
+    }
+
+    /// Applies manifest changes given as matrix parameters, e.g. `public=true`.
+    pub fn update(&mut self, changes: &str) -> Result<()> {
+        for (key, value) in get_params(changes)? {
+            match key.as_str() {
+                "public" => self.public = value.parse()?,
+                _ => return Err(anyhow!("Manifest field {} can not be updated", key)),
+            }
+        }
+        Ok(())
+    }
+
     pub fn make_uri(&self, cid: &Cid) -> Result<String> {
         Ok(format!(
             "kepler://{}/{}",
@@ -77,6 +130,8 @@ impl OrbitMetadata {
 pub enum AuthTokens {
     Tezos(TezosAuthorizationString),
     ZCAP(ZCAPTokens),
+    UCAN(UCANTokens),
+    VP(PresentationTokens),
 }
 
 #[rocket::async_trait]
@@ -89,6 +144,10 @@ impl<'r> FromRequest<'r> for AuthTokens {
                 Self::Tezos(tz)
             } else if let Outcome::Success(zcap) = ZCAPTokens::from_request(request).await {
                 Self::ZCAP(zcap)
+            } else if let Outcome::Success(ucan) = UCANTokens::from_request(request).await {
+                Self::UCAN(ucan)
+            } else if let Outcome::Success(vp) = PresentationTokens::from_request(request).await {
+                Self::VP(vp)
             } else {
                 return Outcome::Failure((
                     Status::Unauthorized,
@@ -99,17 +158,45 @@ impl<'r> FromRequest<'r> for AuthTokens {
     }
 }
 
+impl AuthTokens {
+    /// ID of the delegation invoked by the token, when it is presented along with it.
+    pub fn delegation_id(&self) -> Option<String> {
+        match self {
+            Self::ZCAP(token) => token
+                .delegation_ids()
+                .ok()?
+                .into_iter()
+                .rev()
+                .find(|(_, delegator)| delegator.is_some())
+                .map(|(id, _)| id),
+            _ => None,
+        }
+    }
+}
+
 impl AuthorizationToken for AuthTokens {
     fn action(&self) -> &Action {
         match self {
             Self::Tezos(token) => token.action(),
             Self::ZCAP(token) => token.action(),
+            Self::UCAN(token) => token.action(),
+            Self::VP(token) => token.action(),
         }
     }
     fn target_orbit(&self) -> &Cid {
         match self {
             Self::Tezos(token) => token.target_orbit(),
             Self::ZCAP(token) => token.target_orbit(),
+            Self::UCAN(token) => token.target_orbit(),
+            Self::VP(token) => token.target_orbit(),
+        }
+    }
+    fn invoker(&self) -> Result<DIDURL> {
+        match self {
+            Self::Tezos(token) => token.invoker(),
+            Self::ZCAP(token) => token.invoker(),
+            Self::UCAN(token) => token.invoker(),
+            Self::VP(token) => token.invoker(),
         }
     }
 }
@@ -119,11 +206,25 @@ impl AuthorizationPolicy<AuthTokens> for OrbitMetadata {
         match auth_token {
             AuthTokens::Tezos(token) => self.authorize(token).await,
             AuthTokens::ZCAP(token) => self.authorize(token).await,
+            AuthTokens::UCAN(token) => self.authorize(token).await,
+            AuthTokens::VP(token) => self.authorize(token).await,
         }
     }
 }
 
-struct AbortOnDrop<T>(JoinHandle<T>);
+#[rocket::async_trait]
+impl AuthorizationPolicy<AuthTokens> for Orbit {
+    async fn authorize(&self, auth_token: &AuthTokens) -> Result<()> {
+        match auth_token {
+            AuthTokens::Tezos(token) => self.metadata.authorize(token).await,
+            AuthTokens::ZCAP(token) => self.authorize(token).await,
+            AuthTokens::UCAN(token) => self.authorize(token).await,
+            AuthTokens::VP(token) => self.metadata.authorize(token).await,
+        }
+    }
+}
+
+pub(crate) struct AbortOnDrop<T>(JoinHandle<T>);
 
 impl<T> AbortOnDrop<T> {
     pub fn new(h: JoinHandle<T>) -> Self {
@@ -149,7 +250,366 @@ impl<T> Deref for AbortOnDrop<T> {
 pub struct Orbit {
     task: Arc<AbortOnDrop<()>>,
     pub service: Service,
+    pub revocations: Revocations,
+    pub snapshots: Snapshots,
     metadata: OrbitMetadata,
+    dir: PathBuf,
+    relay: (PeerId, Multiaddr),
+    control: Arc<AbortOnDrop<()>>,
+    // activity on the control topic and peers banned by the orbit's swarm
+    activity: Activity,
+    audit: AuditLog,
+    watch: Arc<AbortOnDrop<()>>,
+    // checked against the orbits reached by reads through the geometry, if set
+    pub(crate) reader: Option<Reader>,
+    // content the token of a write is scoped to, any content if empty
+    pub(crate) write_scopes: Vec<String>,
+}
+
+/// Controller-signed change of an orbit, such as its deletion, announced to its other hosts.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ControlProof {
+    // the signed message as sent in the Authorization header
+    Tezos(String),
+    // the delegation is carried along when the change was invoked by a delegate
+    ZCAP {
+        invocation: KeplerInvocation,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delegation: Option<KeplerDelegation>,
+    },
+}
+
+impl ControlProof {
+    pub fn from_token(token: &AuthTokens) -> Result<Self> {
+        match token {
+            AuthTokens::Tezos(t) => Ok(Self::Tezos(t.to_header()?)),
+            AuthTokens::ZCAP(t) => Ok(Self::ZCAP {
+                invocation: t.invocation.clone(),
+                delegation: t.delegation.clone(),
+            }),
+            _ => Err(anyhow!(
+                "Orbit changes can not be announced with this token type"
+            )),
+        }
+    }
+
+    fn into_token(self) -> Result<AuthTokens> {
+        Ok(match self {
+            Self::Tezos(s) => AuthTokens::Tezos(s.parse()?),
+            Self::ZCAP {
+                invocation,
+                delegation,
+            } => AuthTokens::ZCAP(ZCAPTokens {
+                invocation,
+                delegation,
+            }),
+        })
+    }
+}
+
+/// Replacement of a host's key, announced by the host with its previous key.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HostRotation {
+    #[serde_as(as = "DisplayFromStr")]
+    pub from: PeerId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub to: PeerId,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub addrs: Vec<Multiaddr>,
+}
+
+// messages exchanged between the hosts of an orbit about the orbit itself
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum ControlMessage {
+    Delete(ControlProof),
+    Rotate(HostRotation),
+    Update(ControlProof),
+    // sent by a host before acting on another message, identified by `message_id`
+    Ack(#[serde_as(as = "DisplayFromStr")] Cid),
+}
+
+// how long hosts are given to acknowledge a control message before the orbit is torn down anyway
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn control_topic(id: &str) -> String {
+    format!("{}/control", id)
+}
+
+fn message_id(data: &[u8]) -> Cid {
+    Cid::new_v1(0x55, Code::Blake3_256.digest(data))
+}
+
+fn acknowledge(ipfs: &Ipfs, topic: &str, data: &[u8]) -> Result<()> {
+    ipfs.publish(
+        topic,
+        serde_json::to_vec(&ControlMessage::Ack(message_id(data)))?,
+    )
+}
+
+// a rotation to the same key, which replaces the addresses of a host known to the others
+fn addresses_of(md: &OrbitMetadata, id: PeerId) -> Option<ControlMessage> {
+    match md.hosts.get(&id) {
+        Some(addrs) if !addrs.is_empty() => Some(ControlMessage::Rotate(HostRotation {
+            from: id,
+            to: id,
+            addrs: addrs.clone(),
+        })),
+        _ => None,
+    }
+}
+
+// the swarm is dropped right after a control message is sent, so this waits until the hosts
+// subscribed to the control topic acknowledged it or disconnected
+async fn announce(
+    ipfs: &Ipfs,
+    activity: &Activity,
+    topic: &str,
+    message: &ControlMessage,
+    timeout: Duration,
+) -> Result<()> {
+    let data = serde_json::to_vec(message)?;
+    let id = message_id(&data);
+    ipfs.publish(topic, data)?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let pending: Vec<PeerId> = activity
+            .unacked(&id)
+            .into_iter()
+            .filter(|p| ipfs.is_connected(p))
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        };
+        if tokio::time::Instant::now() >= deadline {
+            tracing::warn!("control message not acknowledged by {:?}", pending);
+            return Ok(());
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+lazy_static! {
+    // the node's rules for signed messages, which announced changes are held to as well
+    static ref CONTROL_AUTH: RwLock<config::Auth> = RwLock::new(Default::default());
+    // signatures of the changes applied by this node, kept until the proofs expire
+    static ref APPLIED_PROOFS: NonceCache = NonceCache::default();
+}
+
+lazy_static! {
+    // held from the policy check of a new orbit until it is created, so concurrent creations
+    // can't both pass the per-controller limit
+    static ref CREATION: tokio::sync::Mutex<()> = Default::default();
+}
+
+/// Serializes orbit creations on this node, the guard is held until the orbit is created.
+pub async fn lock_creation() -> tokio::sync::MutexGuard<'static, ()> {
+    CREATION.lock().await
+}
+
+/// Sets the freshness rules applied to the changes announced by the other hosts of an orbit.
+pub fn set_control_auth(auth: &config::Auth) -> Result<()> {
+    *CONTROL_AUTH.write().map_err(|e| anyhow!(e.to_string()))? = auth.clone();
+    Ok(())
+}
+
+impl ControlProof {
+    // the signature and signing time of the proof
+    fn signed(&self) -> Result<(String, DateTime<Utc>)> {
+        match self {
+            Self::Tezos(s) => {
+                let t: TezosAuthorizationString = s.parse()?;
+                Ok((t.sig.clone(), t.signed_at()?))
+            }
+            Self::ZCAP { invocation, .. } => {
+                let proof = invocation
+                    .proof
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Invocation has no proof"))?;
+                let sig = proof
+                    .jws
+                    .as_ref()
+                    .or_else(|| proof.proof_value.as_ref())
+                    .ok_or_else(|| anyhow!("Invocation proof has no signature"))?;
+                let created = proof
+                    .created
+                    .ok_or_else(|| anyhow!("Invocation proof has no creation time"))?;
+                Ok((sig.clone(), created))
+            }
+        }
+    }
+
+    // a proof is applied once, and only while it is recent, so an old change can't be replayed
+    // onto the orbit or onto a later orbit with the same ID
+    fn check_replay(&self) -> Result<()> {
+        let auth = CONTROL_AUTH
+            .read()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .clone();
+        let now = Utc::now();
+        // proofs are addressed to the host which announced them, which checked their domain
+        let (sig, signed_at) = self.signed()?;
+        let lifetime = chrono::Duration::seconds(auth.lifetime as i64);
+        if signed_at > now + chrono::Duration::seconds(auth.skew as i64) {
+            return Err(anyhow!("Proof is dated in the future"));
+        } else if now > signed_at + lifetime {
+            return Err(anyhow!("Proof has expired"));
+        };
+        APPLIED_PROOFS.use_once(&sig, signed_at + lifetime, now)
+    }
+}
+
+// the orbit is not loaded while changes are checked, so the revocations are passed along
+async fn authorize_control(
+    md: &OrbitMetadata,
+    revocations: &Revocations,
+    token: &AuthTokens,
+) -> Result<()> {
+    if let AuthTokens::ZCAP(t) = token {
+        zcap::check_revocations(&md.controllers, revocations, t)?;
+    };
+    md.authorize(token).await
+}
+
+async fn check_deletion(
+    md: &OrbitMetadata,
+    revocations: &Revocations,
+    proof: ControlProof,
+) -> Result<()> {
+    let token = proof.clone().into_token()?;
+    match (token.action(), token.target_orbit() == &md.id) {
+        (Action::Delete { leave: false }, true) => {
+            authorize_control(md, revocations, &token).await?
+        }
+        _ => return Err(anyhow!("Token is not a deletion of this orbit")),
+    };
+    proof.check_replay()
+}
+
+async fn check_update(
+    md: &OrbitMetadata,
+    revocations: &Revocations,
+    proof: ControlProof,
+) -> Result<()> {
+    let token = proof.clone().into_token()?;
+    match (token.action(), token.target_orbit() == &md.id) {
+        (Action::Update(changes), true) => {
+            // changes this node can't apply are refused before anything is written
+            md.clone().update(changes)?;
+            authorize_control(md, revocations, &token).await?
+        }
+        _ => return Err(anyhow!("Token is not an update of this orbit")),
+    };
+    proof.check_replay()
+}
+
+// hits and misses of the cache of loaded orbits
+pub(crate) async fn cache_stats() -> (u64, u64) {
+    let cache = LOAD_ORBIT_.lock().await;
+    (
+        cache.cache_hits().unwrap_or_default(),
+        cache.cache_misses().unwrap_or_default(),
+    )
+}
+
+// directory which deleted orbits are moved to until their stores are closed
+fn tombstones(path: &Path) -> PathBuf {
+    path.join(".deleted")
+}
+
+/// Removes what is left of orbits deleted before the node was last stopped.
+pub async fn remove_tombstones(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(tombstones(path)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// the orbit directory is moved aside at once, so the orbit can be created again, and removed
+// once in-flight requests release the orbit and its services are dropped
+async fn remove_orbit(dir: PathBuf, relay: (PeerId, Multiaddr)) -> Result<()> {
+    let evicted = LOAD_ORBIT_.lock().await.cache_remove(&(dir.clone(), relay));
+    let name = dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Invalid orbit directory"))?
+        .to_string();
+    metrics::forget_storage(&name);
+    let graveyard = tombstones(dir.parent().unwrap_or(&dir));
+    fs::create_dir_all(&graveyard).await?;
+    let tombstone = graveyard.join(format!("{}-{}", name, Utc::now().timestamp_nanos()));
+    fs::rename(&dir, &tombstone).await?;
+    HOSTED_PEERS.lock().await.cache_clear();
+
+    let services: Option<Weak<_>> = evicted.map(|orbit| Arc::downgrade(&orbit.task));
+    tokio::spawn(async move {
+        // aborted services release their stores the next time they are polled
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if services.as_ref().and_then(Weak::upgrade).is_none() {
+                break;
+            };
+        }
+        if let Err(e) = fs::remove_dir_all(&tombstone).await {
+            tracing::warn!("failed to remove deleted orbit {:?}: {}", tombstone, e);
+        };
+    });
+    Ok(())
+}
+
+// the orbit is reloaded with the changed metadata on its next use
+async fn update_metadata(
+    dir: PathBuf,
+    relay: (PeerId, Multiaddr),
+    change: impl FnOnce(&mut OrbitMetadata) -> Result<()>,
+) -> Result<()> {
+    let mut md: OrbitMetadata = serde_json::from_slice(&fs::read(dir.join("metadata")).await?)?;
+    change(&mut md)?;
+    fs::write(dir.join("metadata"), serde_json::to_vec_pretty(&md)?).await?;
+    LOAD_ORBIT_.lock().await.cache_remove(&(dir, relay));
+    HOSTED_PEERS.lock().await.cache_clear();
+    Ok(())
+}
+
+async fn rotate_host(
+    dir: PathBuf,
+    relay: (PeerId, Multiaddr),
+    rotation: HostRotation,
+) -> Result<()> {
+    update_metadata(dir, relay, |md| {
+        md.hosts.remove(&rotation.from);
+        md.hosts.insert(rotation.to, rotation.addrs);
+        Ok(())
+    })
+    .await
+}
+
+async fn apply_update(dir: PathBuf, relay: (PeerId, Multiaddr), proof: ControlProof) -> Result<()> {
+    let token = proof.into_token()?;
+    match token.action() {
+        Action::Update(changes) => update_metadata(dir, relay, |md| md.update(changes)).await,
+        _ => Err(anyhow!("Token is not an update")),
+    }
+}
+
+// addresses of a host ending with its peer ID, moved to its new peer ID
+fn readdress(addrs: &[Multiaddr], from: &PeerId, to: &PeerId) -> Vec<Multiaddr> {
+    addrs
+        .iter()
+        .map(|addr| {
+            let mut addr = addr.clone();
+            match addr.pop() {
+                Some(Protocol::P2p(id))
+                    if PeerId::from_multihash(id).ok().as_ref() == Some(from) =>
+                {
+                    addr.with(Protocol::P2p((*to).into()))
+                }
+                Some(p) => addr.with(p),
+                None => addr,
+            }
+        })
+        .collect()
 }
 
 fn get_params_vm(method: &str, params: &Map<String, String>) -> Option<DIDURL> {
@@ -181,7 +641,7 @@ pub async fn get_metadata(
     chains: &ExternalApis,
 ) -> Result<OrbitMetadata> {
     let (method, params) = verify_oid(oid, param_str)?;
-    Ok(match (method.as_str(), &chains) {
+    let mut md = match (method.as_str(), &chains) {
         ("tz", ExternalApis { tzkt, .. }) => params_to_tz_orbit(*oid, &params, &tzkt).await?,
         _ => OrbitMetadata {
             id: *oid,
@@ -190,21 +650,190 @@ pub async fn get_metadata(
             read_delegators: vec![],
             write_delegators: vec![],
             revocations: vec![],
+            credential_policies: vec![],
+            quota: None,
+            geometry: None,
+            encrypted: false,
+            public: false,
             hosts: params
                 .get("hosts")
                 .map(|hs| parse_hosts_str(hs))
                 .unwrap_or(Ok(Default::default()))?,
         },
+    };
+    md.geometry = params.get("geometry").map(|g| g.parse()).transpose()?;
+    md.encrypted = params
+        .get("encrypted")
+        .map(|e| e.parse())
+        .transpose()?
+        .unwrap_or(false);
+    md.public = params
+        .get("public")
+        .map(|p| p.parse())
+        .transpose()?
+        .unwrap_or(false);
+    Ok(md)
+}
+
+// a policy entry is either a full DID or a DID method prefix, e.g. `did:pkh:tz`
+fn did_allowed(entry: &str, did: &str) -> bool {
+    did == entry || did.starts_with(&[entry, ":"].concat())
+}
+
+/// IDs of the orbits hosted on this node.
+pub async fn hosted_orbits(path: &Path) -> Result<Vec<Cid>> {
+    Ok(hosted_metadata(path)
+        .await?
+        .into_iter()
+        .map(|md| md.id)
+        .collect())
+}
+
+async fn hosted_metadata(path: &Path) -> Result<Vec<OrbitMetadata>> {
+    let mut hosted = vec![];
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        // the node's own files live next to orbit directories
+        if let Ok(md) = fs::read(entry.path().join("metadata")).await {
+            if let Ok(md) = serde_json::from_slice(&md) {
+                hosted.push(md);
+            }
+        }
+    }
+    Ok(hosted)
+}
+
+/// Manifest of an orbit hosted on this node, read without loading the orbit.
+pub(crate) async fn hosted_orbit_metadata(path: &Path, id: &Cid) -> Result<Option<OrbitMetadata>> {
+    match fs::read(
+        path.join(id.to_string_of_base(Base::Base58Btc)?)
+            .join("metadata"),
+    )
+    .await
+    {
+        Ok(md) => Ok(Some(serde_json::from_slice(&md)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Peers hosting orbits which are also hosted on this node.
+// refreshed when an orbit is created, removed or its manifest changes, and periodically for
+// orbits imported by other means
+#[cached(
+    time = 600,
+    result = true,
+    sync_writes = true,
+    key = "PathBuf",
+    convert = r#"{ path.to_path_buf() }"#
+)]
+pub async fn hosted_peers(path: &Path) -> Result<HashSet<PeerId>> {
+    Ok(hosted_metadata(path)
+        .await?
+        .into_iter()
+        .flat_map(|md| md.hosts.into_iter().map(|(peer, _)| peer))
+        .collect())
+}
+
+// Node policy for hosting new orbits, the optional allowlist is consulted as an additional hook
+// and its entry returned so it can be applied to the new orbit. Callers hold `lock_creation`
+// until the orbit is created, as the limit counts the orbits already on disk
+pub async fn check_creation_policy<A: OrbitAllowList + Sync>(
+    policy: &CreationPolicy,
+    md: &OrbitMetadata,
+    param_str: &str,
+    path: &Path,
+    allowlist: Option<&A>,
+) -> Result<Option<AllowListEntry>> {
+    let (method, _) = verify_oid(&md.id, param_str)?;
+    if !policy.methods.is_empty() && !policy.methods.contains(&method) {
+        return Err(anyhow!("Orbit method {} not allowed", method));
+    };
+
+    if !policy.controllers.is_empty()
+        && !md.controllers.iter().all(|c| {
+            policy
+                .controllers
+                .iter()
+                .any(|entry| did_allowed(entry, &c.did))
+        })
+    {
+        return Err(anyhow!("Orbit controllers not allowed"));
+    };
+
+    if let Some(limit) = policy.limit {
+        let hosted = hosted_metadata(path).await?;
+        for c in &md.controllers {
+            if hosted
+                .iter()
+                .filter(|h| h.controllers.iter().any(|hc| hc.did == c.did))
+                .count()
+                >= limit
+            {
+                return Err(anyhow!("Controller {} has reached its orbit limit", c.did));
+            }
+        }
+    };
+
+    Ok(match allowlist {
+        Some(list) => {
+            let entry = list.is_allowed(&md.id).await?;
+            // an entry without controllers allows the orbit without constraining them
+            if !entry.controllers.is_empty()
+                && !md
+                    .controllers
+                    .iter()
+                    .all(|c| entry.controllers.iter().any(|a| a.did == c.did))
+            {
+                return Err(anyhow!("Orbit controllers not allowed by allowlist"));
+            };
+            Some(entry)
+        }
+        None => None,
     })
 }
 
+// addresses of a host through each relay, `<relay>/p2p/<relay id>/p2p-circuit/p2p/<host>`
+fn circuit_addrs(relays: &[Multiaddr], host: &PeerId) -> Vec<Multiaddr> {
+    relays
+        .iter()
+        .map(|r| {
+            r.clone()
+                .with(Protocol::P2pCircuit)
+                .with(Protocol::P2p((*host).into()))
+        })
+        .collect()
+}
+
+// relays a host can be reached through, from its circuit addresses
+fn relays_of(addrs: &[Multiaddr]) -> Vec<(PeerId, Multiaddr)> {
+    addrs
+        .iter()
+        .filter_map(|addr| {
+            let mut relay: Multiaddr = addr
+                .iter()
+                .take_while(|p| p != &Protocol::P2pCircuit)
+                .collect();
+            // addresses without a circuit are direct
+            if &relay == addr {
+                return None;
+            };
+            match relay.pop() {
+                Some(Protocol::P2p(id)) => Some((PeerId::from_multihash(id).ok()?, relay)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
 // Using Option to distinguish when the orbit already exists from a hard error
 pub async fn create_orbit(
     md: &OrbitMetadata,
     path: PathBuf,
     auth: &[u8],
     relay: (PeerId, Multiaddr),
-    keys_lock: &RwLock<Map<PeerId, Keypair>>,
+    relays: &[Multiaddr],
+    keys: &HostKeys,
 ) -> Result<Option<Orbit>> {
     let dir = path.join(md.id.to_string_of_base(Base::Base58Btc)?);
 
@@ -216,20 +845,42 @@ pub async fn create_orbit(
         .await
         .map_err(|e| anyhow!("Couldn't create dir: {}", e))?;
 
-    let kp = {
-        let mut keys = keys_lock.write().map_err(|e| anyhow!(e.to_string()))?;
-        md.hosts()
-            .find_map(|h| keys.remove(h))
-            .unwrap_or(generate_keypair())
+    // a pending key generated for one of the hosts makes this node that host
+    let mut kp = None;
+    for host in md.hosts() {
+        if let Some(key) = keys.take(host, Utc::now().timestamp())? {
+            kp = Some(key);
+            break;
+        }
+    }
+    let kp = kp.unwrap_or_else(generate_keypair);
+
+    // advertise the relays this host can be reached through
+    let mut md = md.clone();
+    if !relays.is_empty() {
+        let addrs = md.hosts.entry(kp.to_peer_id()).or_default();
+        for addr in circuit_addrs(relays, &kp.to_peer_id()) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
     };
 
-    fs::write(dir.join("metadata"), serde_json::to_vec_pretty(md)?).await?;
+    fs::write(dir.join("metadata"), serde_json::to_vec_pretty(&md)?).await?;
     fs::write(dir.join("access_log"), auth).await?;
     fs::write(dir.join("kp"), kp.to_bytes()).await?;
+    HOSTED_PEERS.lock().await.cache_clear();
 
-    Ok(Some(load_orbit(md.id, path, relay).await.map(|o| {
-        o.ok_or_else(|| anyhow!("Couldn't find newly created orbit"))
-    })??))
+    let orbit = load_orbit(md.id, path, relay)
+        .await?
+        .ok_or_else(|| anyhow!("Couldn't find newly created orbit"))?;
+    // the other hosts learn the relays added above, as with a rotation to the same key
+    if let (false, Some(msg)) = (relays.is_empty(), addresses_of(&md, kp.to_peer_id())) {
+        if let Err(e) = orbit.announce(&msg).await {
+            tracing::warn!("failed to announce the relays of orbit {}: {}", md.id, e);
+        };
+    };
+    Ok(Some(orbit))
 }
 
 pub async fn load_orbit(
@@ -246,7 +897,7 @@ pub async fn load_orbit(
 
 // Not using this function directly because cached cannot handle Result<Option<>> well.
 // 100 orbits => 600 FDs
-// 1min timeout to evict orbits that might have been deleted
+// 1min timeout to evict orbits that might have been deleted by other means than `remove_orbit`
 #[cached(size = 100, time = 60, result = true, sync_writes = true)]
 async fn load_orbit_(dir: PathBuf, relay: (PeerId, Multiaddr)) -> Result<Orbit> {
     let kp = Keypair::from_bytes(&fs::read(dir.join("kp")).await?)?;
@@ -261,25 +912,167 @@ async fn load_orbit_(dir: PathBuf, relay: (PeerId, Multiaddr)) -> Result<Orbit>
 
     // listen for any relayed messages
     ipfs.listen_on(multiaddr!(P2pCircuit))?.next().await;
-    // establish a connection to the relay
-    ipfs.dial_address(&relay.0, relay.1);
-
+    // establish a connection to the node's relay, and to the relays advertised for this host
+    let mut relays = vec![relay.clone()];
+    if let Some(addrs) = md.hosts.get(&ipfs.local_peer_id()) {
+        relays.extend(relays_of(addrs).into_iter().filter(|(r, _)| r != &relay.0));
+    };
+    let mut peers = relays.clone();
     for (peer, addrs) in md.hosts.iter() {
         if peer != &ipfs.local_peer_id() {
-            for addr in addrs.iter() {
-                ipfs.dial_address(peer, addr.clone());
-            }
+            peers.extend(addrs.iter().map(|addr| (*peer, addr.clone())));
         }
     }
+    for (peer, addr) in peers.iter() {
+        ipfs.dial_address(peer, addr.clone());
+    }
+
+    // relays and other hosts are redialed when they disconnect, a host stays reachable through
+    // its remaining relays in the meantime
+    let watch_ipfs = ipfs.clone();
+    let watch = Arc::new(AbortOnDrop::new(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            for (peer, addr) in peers.iter() {
+                if !watch_ipfs.is_connected(peer) {
+                    tracing::debug!("redialing {} at {}", peer, addr);
+                    watch_ipfs.dial_address(peer, addr.clone());
+                }
+            }
+        }
+    })));
 
     let task_ipfs = ipfs.clone();
 
+    let activity = Activity::default();
+
+    let revocations = Revocations::start(Store::new(
+        format!("{}/revocations", &id),
+        ipfs.clone(),
+        sled::open(dir.join(&id).with_extension("revdb"))?,
+    )?)?;
+
+    let topic = control_topic(&id);
+    let mut control_events = ipfs.subscribe(&topic)?;
+    let (control_md, control_dir, control_relay) = (md.clone(), dir.clone(), relay.clone());
+    let (control_ipfs, control_activity) = (ipfs.clone(), activity.clone());
+    let control_revocations = revocations.clone();
+    let control = Arc::new(AbortOnDrop::new(tokio::spawn(async move {
+        while let Some(event) = control_events.next().await {
+            let (peer, data) = match event {
+                GossipEvent::Message(peer, data) => {
+                    metrics::gossip_message("control");
+                    (peer, data)
+                }
+                GossipEvent::Subscribed(peer) => {
+                    control_activity.subscribed(peer, true);
+                    // hosts joining later learn the relays this host was created with
+                    let addrs = addresses_of(&control_md, control_ipfs.local_peer_id());
+                    if let (true, Some(msg)) = (control_md.hosts.contains_key(&peer), addrs) {
+                        if let Err(e) = serde_json::to_vec(&msg)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|data| control_ipfs.publish(&topic, data))
+                        {
+                            tracing::debug!("failed to announce host addresses: {}", e);
+                        };
+                    };
+                    continue;
+                }
+                GossipEvent::Unsubscribed(peer) => {
+                    control_activity.subscribed(peer, false);
+                    continue;
+                }
+            };
+            let checked = match serde_json::from_slice(&data) {
+                Ok(ControlMessage::Delete(proof)) => {
+                    check_deletion(&control_md, &control_revocations, proof.clone())
+                        .await
+                        .map(|()| ControlMessage::Delete(proof))
+                }
+                // only a host can announce its own rotation
+                Ok(ControlMessage::Rotate(rotation))
+                    if rotation.from == peer && control_md.hosts.contains_key(&peer) =>
+                {
+                    Ok(ControlMessage::Rotate(rotation))
+                }
+                Ok(ControlMessage::Rotate(_)) => Err(anyhow!("Rotation not sent by the host")),
+                Ok(ControlMessage::Update(proof)) => {
+                    check_update(&control_md, &control_revocations, proof.clone())
+                        .await
+                        .map(|()| ControlMessage::Update(proof))
+                }
+                Ok(ControlMessage::Ack(id)) => {
+                    control_activity.acked(peer, id);
+                    continue;
+                }
+                Err(e) => Err(anyhow!(e)),
+            };
+            if checked.is_ok() {
+                if let Err(e) = acknowledge(&control_ipfs, &topic, &data) {
+                    tracing::debug!("failed to acknowledge control message: {}", e);
+                };
+            };
+            let (dir, relay) = (control_dir.clone(), control_relay.clone());
+            // detached, as evicting the orbit aborts this task
+            match checked {
+                Ok(ControlMessage::Delete(_)) => {
+                    tracing::info!("orbit {} deleted by {}", control_md.id, peer);
+                    tokio::spawn(async move {
+                        if let Err(e) = remove_orbit(dir, relay).await {
+                            tracing::error!("failed to remove orbit: {}", e);
+                        }
+                    });
+                    return;
+                }
+                // addresses announced again, which this host already knows
+                Ok(ControlMessage::Rotate(rotation))
+                    if rotation.from == rotation.to
+                        && control_md.hosts.get(&peer) == Some(&rotation.addrs) => {}
+                Ok(ControlMessage::Rotate(rotation)) => {
+                    tracing::info!(
+                        "host {} of orbit {} rotated to {}",
+                        peer,
+                        control_md.id,
+                        rotation.to
+                    );
+                    tokio::spawn(async move {
+                        if let Err(e) = rotate_host(dir, relay, rotation).await {
+                            tracing::error!("failed to rotate orbit host: {}", e);
+                        }
+                    });
+                    return;
+                }
+                Ok(ControlMessage::Update(proof)) => {
+                    tracing::info!("orbit {} manifest updated by {}", control_md.id, peer);
+                    tokio::spawn(async move {
+                        if let Err(e) = apply_update(dir, relay, proof).await {
+                            tracing::error!("failed to update orbit manifest: {}", e);
+                        }
+                    });
+                    return;
+                }
+                Ok(ControlMessage::Ack(_)) => (),
+                Err(e) => tracing::debug!("ignoring control message from {}: {}", peer, e),
+            }
+        }
+    })));
+
+    let snapshots = Snapshots::start(Store::new(
+        format!("{}/snapshots", &id),
+        ipfs.clone(),
+        sled::open(dir.join(&id).with_extension("snapdb"))?,
+    )?)?;
+
     let db = sled::open(dir.join(&id).with_extension("ks3db"))?;
 
     let service_store = Store::new(id, ipfs, db)?;
     let service = Service::start(service_store)?;
 
     let st = service.store.clone();
+    let rev = revocations.0.store.clone();
+    let snaps = snapshots.service.store.clone();
+    let task_activity = activity.clone();
 
     let task = Arc::new(AbortOnDrop::new(tokio::spawn(async move {
         let mut events = st.ipfs.swarm_events();
@@ -290,7 +1083,10 @@ async fn load_orbit_(dir: PathBuf, relay: (PeerId, Multiaddr)) -> Result<Orbit>
                         tracing::debug!("dialing peer {}", p);
                         task_ipfs.dial(&p);
                         st.request_heads();
+                        rev.request_heads();
+                        snaps.request_heads();
                     } else {
+                        task_activity.banned(p);
                         task_ipfs.ban(p)
                     };
                 }
@@ -300,10 +1096,22 @@ async fn load_orbit_(dir: PathBuf, relay: (PeerId, Multiaddr)) -> Result<Orbit>
         }
     })));
 
+    let audit = AuditLog::new(dir.join("audit_log"));
+
     Ok(Orbit {
         service,
+        revocations,
+        snapshots,
         task,
         metadata: md,
+        dir,
+        relay,
+        control,
+        activity,
+        audit,
+        watch,
+        reader: None,
+        write_scopes: vec![],
     })
 }
 
@@ -365,18 +1173,40 @@ impl ContentAddressedStorage for Orbit {
         content: &[u8],
         codec: SupportedCodecs,
     ) -> Result<Cid, <Self as ContentAddressedStorage>::Error> {
+        self.check_writable()?;
+        self.check_scope(content)?;
         self.service.ipfs.put(content, codec).await
     }
     async fn get(
         &self,
         address: &Cid,
     ) -> Result<Option<Vec<u8>>, <Self as ContentAddressedStorage>::Error> {
+        if let Some(members) = self.members().await? {
+            for member in members {
+                if let Some(content) = ContentAddressedStorage::get(&member, address).await? {
+                    return Ok(Some(content));
+                }
+            }
+            return Ok(None);
+        };
         ContentAddressedStorage::get(&self.service.ipfs, address).await
     }
     async fn delete(&self, address: &Cid) -> Result<(), <Self as ContentAddressedStorage>::Error> {
+        self.check_writable()?;
         self.service.ipfs.delete(address).await
     }
     async fn list(&self) -> Result<Vec<Cid>, <Self as ContentAddressedStorage>::Error> {
+        if let Some(members) = self.members().await? {
+            let mut cids = vec![];
+            for member in members {
+                for cid in ContentAddressedStorage::list(&member).await? {
+                    if !cids.contains(&cid) {
+                        cids.push(cid);
+                    }
+                }
+            }
+            return Ok(cids);
+        };
         self.service.ipfs.list().await
     }
 }
@@ -389,6 +1219,10 @@ impl Deref for Orbit {
 }
 
 impl Orbit {
+    pub fn metadata(&self) -> &OrbitMetadata {
+        &self.metadata
+    }
+
     pub fn read_delegators(&self) -> &[DIDURL] {
         &self.metadata.read_delegators
     }
@@ -397,6 +1231,112 @@ impl Orbit {
         &self.metadata.write_delegators
     }
 
+    // directory holding all orbits of this node
+    pub(crate) fn path(&self) -> PathBuf {
+        self.dir.parent().map(PathBuf::from).unwrap_or_default()
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    pub(crate) fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    pub(crate) fn control_topic(&self) -> String {
+        control_topic(&self.service.store.id)
+    }
+
+    pub(crate) fn relay(&self) -> (PeerId, Multiaddr) {
+        self.relay.clone()
+    }
+
+    async fn announce(&self, message: &ControlMessage) -> Result<()> {
+        announce(
+            &self.service.store.ipfs,
+            &self.activity,
+            &self.control_topic(),
+            message,
+            ANNOUNCE_TIMEOUT,
+        )
+        .await
+    }
+
+    /// Stops hosting the orbit on this node and removes its data. When a deletion proof is given
+    /// it is announced to the other hosts first, which remove their copies as well.
+    pub async fn delete(
+        self,
+        relay: (PeerId, Multiaddr),
+        announce: Option<ControlProof>,
+    ) -> Result<()> {
+        if let Some(proof) = announce {
+            proof.check_replay()?;
+            self.announce(&ControlMessage::Delete(proof)).await?;
+        };
+        let dir = self.dir.clone();
+        drop(self);
+        remove_orbit(dir, relay).await
+    }
+
+    /// Replaces this node's host key for the orbit. The new peer ID and its addresses are
+    /// announced to the other hosts with the previous key before the orbit is reloaded.
+    pub async fn rotate_key(self, relays: &[Multiaddr]) -> Result<PeerId> {
+        let from = self.service.store.ipfs.local_peer_id();
+        let kp = generate_keypair();
+        let to = kp.to_peer_id();
+
+        let mut addrs = readdress(
+            self.metadata
+                .hosts
+                .get(&from)
+                .map(|a| a.as_slice())
+                .unwrap_or(&[]),
+            &from,
+            &to,
+        );
+        for addr in circuit_addrs(relays, &to) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        let rotation = HostRotation { from, to, addrs };
+
+        self.announce(&ControlMessage::Rotate(rotation.clone()))
+            .await?;
+        fs::write(self.dir.join("kp"), kp.to_bytes()).await?;
+        let (dir, relay) = (self.dir.clone(), self.relay.clone());
+        drop(self);
+        rotate_host(dir, relay, rotation).await?;
+        Ok(to)
+    }
+
+    /// Changes the orbit's manifest on this node, and announces the controller-signed update to
+    /// the other hosts.
+    pub async fn update_manifest(self, proof: ControlProof) -> Result<()> {
+        check_update(&self.metadata, &self.revocations, proof.clone()).await?;
+        self.announce(&ControlMessage::Update(proof.clone()))
+            .await?;
+        let (dir, relay) = (self.dir.clone(), self.relay.clone());
+        drop(self);
+        apply_update(dir, relay, proof).await
+    }
+
+    /// Refuses content which the token the orbit was authorized with is not scoped to.
+    pub fn check_scope(&self, content: &[u8]) -> Result<()> {
+        // content is stored as raw blocks, so its CID is known before it is written
+        let cid = Cid::new_v1(0x55, Code::Blake3_256.digest(content)).to_string();
+        match Action::Put(self.write_scopes.clone()).covers(&cid) {
+            true => Ok(()),
+            false => Err(anyhow!("Token not scoped to {}", cid)),
+        }
+    }
+
+    // bytes of content stored in the orbit, counted as objects are written and removed
+    pub fn storage_size(&self) -> Result<u64> {
+        self.service.store.usage()
+    }
+
     // async fn update(&self, _update: Self::UpdateMessage) -> Result<(), <Self as Orbit>::Error> {
     //     todo!()
     // }
@@ -425,3 +1365,196 @@ async fn parameters() -> Result<()> {
     let _md = get_metadata(&oid, params, &Default::default()).await?;
     Ok(())
 }
+
+#[test]
+async fn manifest_update() -> Result<()> {
+    let params = r#"did;did=did%3Akey%3Az6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom;hosts=12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly%3A%2Fip4%2F127.0.0.1%2Ftcp%2F8081%2Fp2p%2F12D3KooWG4GKKKocGcX9pfdcdQncaLM73mY4X6TwB6tT48g1ijTY%2Fp2p-circuit%2Fp2p%2F12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly;vm=z6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom"#;
+    let oid: Cid = "zCT5htkeCSu7WefuBKYUidQJkRgEvEGZQrFVqYS6ZJVM6zwLCRcF".parse()?;
+    let mut md = get_metadata(&oid, params, &Default::default()).await?;
+    assert!(!md.public);
+    md.update("public=true")?;
+    assert!(md.public);
+    assert!(md.update("public=yes").is_err());
+    assert!(md.update("controllers=did%3Aexample%3Aeve").is_err());
+    assert!(md.public);
+    Ok(())
+}
+
+#[test]
+async fn announced_addresses() -> Result<()> {
+    let params = r#"did;did=did%3Akey%3Az6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom;hosts=12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly%3A%2Fip4%2F127.0.0.1%2Ftcp%2F8081%2Fp2p%2F12D3KooWG4GKKKocGcX9pfdcdQncaLM73mY4X6TwB6tT48g1ijTY%2Fp2p-circuit%2Fp2p%2F12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly;vm=z6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom"#;
+    let oid: Cid = "zCT5htkeCSu7WefuBKYUidQJkRgEvEGZQrFVqYS6ZJVM6zwLCRcF".parse()?;
+    let md = get_metadata(&oid, params, &Default::default()).await?;
+    let host: PeerId = "12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly".parse()?;
+    match addresses_of(&md, host) {
+        Some(ControlMessage::Rotate(r)) => {
+            assert_eq!((r.from, r.to), (host, host));
+            assert_eq!(Some(&r.addrs), md.hosts.get(&host));
+        }
+        _ => panic!("host addresses not announced"),
+    };
+    assert!(addresses_of(&md, generate_keypair().to_peer_id()).is_none());
+    Ok(())
+}
+
+#[test]
+async fn rotated_addresses() -> Result<()> {
+    let (from, to) = (
+        generate_keypair().to_peer_id(),
+        generate_keypair().to_peer_id(),
+    );
+    let relay: Multiaddr =
+        "/ip4/127.0.0.1/tcp/8081/p2p/12D3KooWG4GKKKocGcX9pfdcdQncaLM73mY4X6TwB6tT48g1ijTY"
+            .parse()?;
+    let direct: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse()?;
+    let addrs = vec![
+        circuit_addrs(&[relay.clone()], &from).remove(0),
+        direct.clone(),
+    ];
+    assert_eq!(
+        readdress(&addrs, &from, &to),
+        vec![circuit_addrs(&[relay], &to).remove(0), direct]
+    );
+    Ok(())
+}
+
+#[test]
+async fn creation_policy() -> Result<()> {
+    use crate::allow_list::AllowList;
+    let params = r#"did;did=did%3Akey%3Az6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom;hosts=12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly%3A%2Fip4%2F127.0.0.1%2Ftcp%2F8081%2Fp2p%2F12D3KooWG4GKKKocGcX9pfdcdQncaLM73mY4X6TwB6tT48g1ijTY%2Fp2p-circuit%2Fp2p%2F12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly;vm=z6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom"#;
+    let oid: Cid = "zCT5htkeCSu7WefuBKYUidQJkRgEvEGZQrFVqYS6ZJVM6zwLCRcF".parse()?;
+    let md = get_metadata(&oid, params, &Default::default()).await?;
+    let tmp = tempdir::TempDir::new("policy")?;
+    let check = |policy: CreationPolicy| {
+        let md = md.clone();
+        let path = tmp.path().to_path_buf();
+        async move { check_creation_policy::<AllowList>(&policy, &md, params, &path, None).await }
+    };
+
+    assert!(check(Default::default()).await.is_ok());
+    assert!(check(CreationPolicy {
+        controllers: vec!["did:key".into()],
+        methods: vec!["did".into()],
+        ..Default::default()
+    })
+    .await
+    .is_ok());
+    assert!(check(CreationPolicy {
+        controllers: vec!["did:pkh:tz".into()],
+        ..Default::default()
+    })
+    .await
+    .is_err());
+    assert!(check(CreationPolicy {
+        methods: vec!["tz".into()],
+        ..Default::default()
+    })
+    .await
+    .is_err());
+
+    let hosted = tmp.path().join("hosted");
+    fs::create_dir(&hosted).await?;
+    fs::write(hosted.join("metadata"), serde_json::to_vec(&md)?).await?;
+    assert!(check(CreationPolicy {
+        limit: Some(2),
+        ..Default::default()
+    })
+    .await
+    .is_ok());
+    assert!(check(CreationPolicy {
+        limit: Some(1),
+        ..Default::default()
+    })
+    .await
+    .is_err());
+    Ok(())
+}
+
+#[test]
+async fn relay_addresses() -> Result<()> {
+    let relay: PeerId = "12D3KooWG4GKKKocGcX9pfdcdQncaLM73mY4X6TwB6tT48g1ijTY".parse()?;
+    let host: PeerId = "12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly".parse()?;
+    let relay_addr: Multiaddr = "/ip4/127.0.0.1/tcp/8081".parse()?;
+    let addrs = circuit_addrs(
+        &[relay_addr.clone().with(Protocol::P2p(relay.into()))],
+        &host,
+    );
+    assert_eq!(
+        addrs,
+        vec![format!(
+            "/ip4/127.0.0.1/tcp/8081/p2p/{}/p2p-circuit/p2p/{}",
+            relay, host
+        )
+        .parse()?]
+    );
+    assert_eq!(relays_of(&addrs), vec![(relay, relay_addr.clone())]);
+    // direct addresses have no relay
+    assert!(relays_of(&[relay_addr.with(Protocol::P2p(host.into()))]).is_empty());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn announced_deletion() -> Result<()> {
+    use rocket::tokio::sync::oneshot;
+    let tmp = tempdir::TempDir::new("announce")?;
+    let node = |name: &str| {
+        let mut cfg = Config::new(&tmp.path().join(name), generate_keypair());
+        cfg.network.mdns = None;
+        Ipfs::new(cfg)
+    };
+    let (alice, bob) = (node("alice").await?, node("bob").await?);
+    let (topic, bob_id) = ("test/control", bob.local_peer_id());
+    let bob_addr = multiaddr!(Ip4([127u8, 0u8, 0u8, 1u8]), Tcp(10002u16));
+    bob.listen_on(bob_addr.clone())?.next().await;
+
+    let activity = Activity::default();
+    let (mut alice_events, mut bob_events) = (alice.subscribe(topic)?, bob.subscribe(topic)?);
+    let alice_activity = activity.clone();
+    tokio::spawn(async move {
+        while let Some(event) = alice_events.next().await {
+            match event {
+                GossipEvent::Subscribed(peer) => alice_activity.subscribed(peer, true),
+                GossipEvent::Unsubscribed(peer) => alice_activity.subscribed(peer, false),
+                GossipEvent::Message(peer, data) => {
+                    if let Ok(ControlMessage::Ack(id)) = serde_json::from_slice(&data) {
+                        alice_activity.acked(peer, id)
+                    }
+                }
+            }
+        }
+    });
+    // bob acknowledges the deletion and drops its swarm right away, as a host removing the orbit
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        while let Some(event) = bob_events.next().await {
+            if let GossipEvent::Message(_, data) = event {
+                acknowledge(&bob, topic, &data).unwrap();
+                let _ = tx.send(serde_json::from_slice::<ControlMessage>(&data).ok());
+                return;
+            }
+        }
+    });
+
+    alice.dial_address(&bob_id, bob_addr);
+    let dummy = message_id(b"subscribed");
+    let wait = tokio::time::Instant::now();
+    while !activity.unacked(&dummy).contains(&bob_id) {
+        assert!(
+            wait.elapsed() < Duration::from_secs(10),
+            "bob never subscribed"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let deletion = ControlMessage::Delete(ControlProof::Tezos("deletion".into()));
+    let sent = tokio::time::Instant::now();
+    announce(&alice, &activity, topic, &deletion, Duration::from_secs(30)).await?;
+    assert!(sent.elapsed() < Duration::from_secs(30));
+    // alice's swarm can go once the announcement returned, bob got the message anyway
+    drop(alice);
+    assert!(matches!(
+        rx.await?,
+        Some(ControlMessage::Delete(ControlProof::Tezos(s))) if s == "deletion"
+    ));
+    Ok(())
+}
@@ -0,0 +1,227 @@
+//! Introspection of the embedded IPFS node of an orbit, for debugging replication.
+
+use crate::orbit::Orbit;
+use chrono::Utc;
+use ipfs_embed::{Multiaddr, PeerId};
+use libipld::cid::Cid;
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// Peer activity seen on one gossip topic, along with banned peers and blocks being fetched.
+#[derive(Clone, Default)]
+pub struct Activity(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    subscribers: HashSet<PeerId>,
+    heads: HashMap<PeerId, SeenHeads>,
+    banned: HashSet<PeerId>,
+    wants: HashMap<Cid, usize>,
+    // subscribers which acknowledged each control message
+    acks: HashMap<Cid, HashSet<PeerId>>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SeenHeads {
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub heads: Vec<Cid>,
+    // unix timestamp of the last heads message
+    pub seen: i64,
+}
+
+/// A block being fetched from peers, until dropped.
+pub struct Want {
+    activity: Activity,
+    cid: Cid,
+}
+
+impl Drop for Want {
+    fn drop(&mut self) {
+        self.activity.update(|s| {
+            if let Some(n) = s.wants.get_mut(&self.cid) {
+                *n -= 1;
+                if *n == 0 {
+                    s.wants.remove(&self.cid);
+                }
+            }
+        })
+    }
+}
+
+impl Activity {
+    // a poisoned lock only means a panic while recording, the state is still usable
+    fn update<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+        match self.0.lock() {
+            Ok(mut s) => f(&mut s),
+            Err(e) => f(&mut e.into_inner()),
+        }
+    }
+
+    pub fn subscribed(&self, peer: PeerId, subscribed: bool) {
+        self.update(|s| {
+            if subscribed {
+                s.subscribers.insert(peer);
+            } else {
+                s.subscribers.remove(&peer);
+            }
+        })
+    }
+
+    pub fn heads_seen(&self, peer: PeerId, heads: &[Cid]) {
+        let seen = SeenHeads {
+            heads: heads.to_vec(),
+            seen: Utc::now().timestamp(),
+        };
+        self.update(|s| s.heads.insert(peer, seen));
+    }
+
+    pub fn banned(&self, peer: PeerId) {
+        self.update(|s| s.banned.insert(peer));
+    }
+
+    pub fn acked(&self, peer: PeerId, message: Cid) {
+        self.update(|s| {
+            s.acks.entry(message).or_default().insert(peer);
+        })
+    }
+
+    /// Subscribers which haven't acknowledged a message yet.
+    pub fn unacked(&self, message: &Cid) -> HashSet<PeerId> {
+        self.update(|s| {
+            let acked = s.acks.get(message);
+            s.subscribers
+                .iter()
+                .filter(|p| !acked.map_or(false, |a| a.contains(p)))
+                .cloned()
+                .collect()
+        })
+    }
+
+    pub fn want(&self, cid: Cid) -> Want {
+        self.update(|s| *s.wants.entry(cid).or_default() += 1);
+        Want {
+            activity: self.clone(),
+            cid,
+        }
+    }
+
+    fn topic(&self) -> Topic {
+        self.update(|s| Topic {
+            subscribers: s.subscribers.iter().cloned().collect(),
+            heads: s.heads.clone().into_iter().collect(),
+        })
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone)]
+pub struct Topic {
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub subscribers: Vec<PeerId>,
+    // heads last announced by each peer, empty for topics without heads
+    #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+    pub heads: BTreeMap<PeerId, SeenHeads>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone)]
+pub struct Connection {
+    #[serde_as(as = "DisplayFromStr")]
+    pub peer: PeerId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub address: Multiaddr,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone)]
+pub struct OrbitPeers {
+    #[serde_as(as = "DisplayFromStr")]
+    pub orbit: Cid,
+    #[serde_as(as = "DisplayFromStr")]
+    pub peer_id: PeerId,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub listeners: Vec<Multiaddr>,
+    pub connections: Vec<Connection>,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub banned: Vec<PeerId>,
+    pub topics: BTreeMap<String, Topic>,
+    // blocks requested from peers which haven't arrived yet
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub wants: Vec<Cid>,
+}
+
+impl Orbit {
+    pub fn peers(&self) -> OrbitPeers {
+        let ipfs = &self.service.store.ipfs;
+        let stores = [
+            &self.service.store,
+            &self.revocations.0.store,
+            &self.snapshots.service.store,
+        ];
+        let mut topics: BTreeMap<String, Topic> = stores
+            .iter()
+            .map(|s| (s.id.clone(), s.activity.topic()))
+            .collect();
+        topics.insert(self.control_topic(), self.activity().topic());
+
+        let mut wants: Vec<Cid> = stores
+            .iter()
+            .flat_map(|s| {
+                s.activity
+                    .update(|s| s.wants.keys().cloned().collect::<Vec<_>>())
+            })
+            .collect();
+        wants.sort();
+        wants.dedup();
+
+        OrbitPeers {
+            orbit: *self.id(),
+            peer_id: ipfs.local_peer_id(),
+            listeners: ipfs.listeners(),
+            connections: ipfs
+                .connections()
+                .into_iter()
+                .map(|(peer, address)| Connection { peer, address })
+                .collect(),
+            banned: self
+                .activity()
+                .update(|s| s.banned.iter().cloned().collect()),
+            topics,
+            wants,
+        }
+    }
+}
+
+#[test]
+async fn activity() {
+    let activity = Activity::default();
+    let peer = PeerId::random();
+    let cid: Cid = "zCT5htkeBtA6Qu5YF4vPkQcfeqy3pY4m8zxGdUKUiPgtPEbY3rHy"
+        .parse()
+        .unwrap();
+    activity.subscribed(peer, true);
+    activity.heads_seen(peer, &[cid]);
+    let topic = activity.topic();
+    assert_eq!(topic.subscribers, vec![peer]);
+    assert_eq!(
+        topic.heads.get(&peer).map(|h| h.heads.clone()),
+        Some(vec![cid])
+    );
+
+    assert!(activity.unacked(&cid).contains(&peer));
+    activity.acked(peer, cid);
+    assert!(activity.unacked(&cid).is_empty());
+    activity.subscribed(peer, false);
+    assert!(activity.topic().subscribers.is_empty());
+
+    let (first, second) = (activity.want(cid), activity.want(cid));
+    drop(first);
+    assert_eq!(activity.update(|s| s.wants.len()), 1);
+    drop(second);
+    assert!(activity.update(|s| s.wants.is_empty()));
+}
@@ -1,8 +1,11 @@
+use crate::{config, orbit::hosted_peers};
 use anyhow::Result;
 use libp2p::{
     core::{
+        connection::ConnectedPoint,
         identity::Keypair,
-        multiaddr::multiaddr,
+        multiaddr::{multiaddr, Protocol},
+        muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent},
         transport::MemoryTransport,
         upgrade::{SelectUpgrade, Version},
         Multiaddr, PeerId, Transport,
@@ -10,30 +13,346 @@ use libp2p::{
     dns::TokioDnsConfig as DnsConfig,
     mplex::MplexConfig,
     noise::{self, NoiseConfig, X25519Spec},
-    relay::new_transport_and_behaviour,
-    swarm::Swarm,
+    relay::{new_transport_and_behaviour, RelayConfig},
+    swarm::{SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig as TcpConfig,
     yamux::YamuxConfig,
 };
 use rocket::{
-    futures::stream::StreamExt,
-    tokio::{spawn, task::JoinHandle},
+    futures::{ready, stream::StreamExt},
+    tokio::{
+        spawn,
+        task::JoinHandle,
+        time::{sleep, Sleep},
+    },
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
 };
-use std::time::Duration;
+
+/// Counters of the relay's activity.
+#[derive(Default, Debug)]
+pub struct RelayStats {
+    // currently open connections
+    pub connections: AtomicU64,
+    // currently relayed circuits
+    pub circuits: AtomicU64,
+    // circuits refused or closed because of the limits, and connections of peers hosting no orbit here
+    pub denied: AtomicU64,
+    // bytes carried by the circuits, in both directions
+    pub bytes: AtomicU64,
+}
 
 pub struct RelayNode {
     pub port: u16,
     pub id: PeerId,
+    external: Vec<Multiaddr>,
+    relays: Vec<Multiaddr>,
+    stats: Arc<RelayStats>,
     task: JoinHandle<()>,
 }
 
+// circuit limits shared by all connections of the relay
+struct Limits {
+    config: config::RelayLimits,
+    // circuits currently relayed for each peer
+    peers: Mutex<HashMap<PeerId, u32>>,
+    stats: Arc<RelayStats>,
+}
+
+impl Limits {
+    fn new(config: &config::RelayLimits, stats: Arc<RelayStats>) -> Self {
+        Self {
+            config: config.clone(),
+            peers: Mutex::new(HashMap::new()),
+            stats,
+        }
+    }
+
+    // reserves a circuit for the peer, unless it or the relay already has too many open
+    fn open(&self, peer: &PeerId) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let open = peers.get(peer).copied().unwrap_or(0);
+        let total = self.stats.circuits.load(Ordering::Relaxed);
+        if self.config.circuits.map_or(false, |max| open >= max)
+            || self.config.total.map_or(false, |max| total >= max as u64)
+        {
+            self.stats.denied.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+        peers.insert(*peer, open + 1);
+        self.stats.circuits.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn close(&self, peer: &PeerId) {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(open) = peers.get_mut(peer) {
+            *open -= 1;
+            if *open == 0 {
+                peers.remove(peer);
+            };
+        };
+        self.stats.circuits.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// bytes and age of a circuit, which is closed once it carried too much or stayed open too long
+struct Meter {
+    // registered with the task polling the circuit, so idle circuits are woken up to be closed
+    expiry: Option<Pin<Box<Sleep>>>,
+    bytes: u64,
+    limited: bool,
+}
+
+impl Meter {
+    fn new(limits: &Limits) -> Self {
+        Self {
+            expiry: limits
+                .config
+                .duration
+                .map(|secs| Box::pin(sleep(Duration::from_secs(secs)))),
+            bytes: 0,
+            limited: false,
+        }
+    }
+
+    fn check(&mut self, cx: &mut Context<'_>, limits: &Limits) -> io::Result<()> {
+        let expired = self
+            .expiry
+            .as_mut()
+            .map_or(false, |expiry| expiry.as_mut().poll(cx).is_ready());
+        let exceeded = match limits.config.bytes {
+            Some(bytes) if self.bytes >= bytes => "byte",
+            _ if expired => "duration",
+            _ => return Ok(()),
+        };
+        if !self.limited {
+            self.limited = true;
+            limits.stats.denied.fetch_add(1, Ordering::Relaxed);
+            tracing::info!("closing relayed circuit, {} limit reached", exceeded);
+        };
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("relay {} limit reached", exceeded),
+        ))
+    }
+
+    fn count(&mut self, n: usize, limits: &Limits) {
+        self.bytes += n as u64;
+        limits.stats.bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+// a substream of a relay connection, metered when a remote peer opened it to request a circuit
+struct Circuit<S> {
+    inner: S,
+    meter: Option<Meter>,
+}
+
+// wraps the muxer of a remote peer's connection, so that the limits apply to each circuit it
+// requests while the connection itself, which a host keeps open to be reachable, is not capped
+struct Relayed<M> {
+    inner: M,
+    peer: PeerId,
+    limits: Arc<Limits>,
+}
+
+impl<M: StreamMuxer> Relayed<M> {
+    fn metered<T>(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Circuit<M::Substream>,
+        op: impl FnOnce(&M, &mut Context<'_>, &mut M::Substream) -> Poll<Result<T, M::Error>>,
+        count: impl FnOnce(&T) -> usize,
+    ) -> Poll<io::Result<T>> {
+        if let Some(meter) = &mut s.meter {
+            meter.check(cx, &self.limits)?;
+        };
+        let res: Poll<io::Result<T>> = op(&self.inner, cx, &mut s.inner).map_err(Into::into);
+        if let (Some(meter), Poll::Ready(Ok(t))) = (&mut s.meter, &res) {
+            meter.count(count(t), &self.limits);
+        };
+        res
+    }
+}
+
+impl<M: StreamMuxer> StreamMuxer for Relayed<M> {
+    type Substream = Circuit<M::Substream>;
+    type OutboundSubstream = M::OutboundSubstream;
+    type Error = io::Error;
+
+    fn poll_event(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<StreamMuxerEvent<Self::Substream>>> {
+        loop {
+            let substream = match ready!(self.inner.poll_event(cx).map_err(Into::into))? {
+                StreamMuxerEvent::InboundSubstream(s) => s,
+                StreamMuxerEvent::AddressChange(addr) => {
+                    return Poll::Ready(Ok(StreamMuxerEvent::AddressChange(addr)))
+                }
+            };
+            // the relay only speaks the relay protocol, so every inbound substream is a circuit
+            if self.limits.open(&self.peer) {
+                return Poll::Ready(Ok(StreamMuxerEvent::InboundSubstream(Circuit {
+                    inner: substream,
+                    meter: Some(Meter::new(&self.limits)),
+                })));
+            };
+            tracing::info!("refusing circuit for {}, circuit limit reached", self.peer);
+            self.inner.destroy_substream(substream);
+        }
+    }
+
+    fn open_outbound(&self) -> Self::OutboundSubstream {
+        self.inner.open_outbound()
+    }
+
+    // circuits opened towards a destination mirror the metered side of the source
+    fn poll_outbound(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::OutboundSubstream,
+    ) -> Poll<io::Result<Self::Substream>> {
+        self.inner
+            .poll_outbound(cx, s)
+            .map_ok(|inner| Circuit { inner, meter: None })
+            .map_err(Into::into)
+    }
+
+    fn destroy_outbound(&self, s: Self::OutboundSubstream) {
+        self.inner.destroy_outbound(s)
+    }
+
+    fn read_substream(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::Substream,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.metered(cx, s, |m, cx, s| m.read_substream(cx, s, buf), |n| *n)
+    }
+
+    fn write_substream(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::Substream,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.metered(cx, s, |m, cx, s| m.write_substream(cx, s, buf), |n| *n)
+    }
+
+    fn flush_substream(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::Substream,
+    ) -> Poll<io::Result<()>> {
+        self.inner
+            .flush_substream(cx, &mut s.inner)
+            .map_err(Into::into)
+    }
+
+    fn shutdown_substream(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::Substream,
+    ) -> Poll<io::Result<()>> {
+        self.inner
+            .shutdown_substream(cx, &mut s.inner)
+            .map_err(Into::into)
+    }
+
+    fn destroy_substream(&self, s: Self::Substream) {
+        if s.meter.is_some() {
+            self.limits.close(&self.peer);
+        };
+        self.inner.destroy_substream(s.inner)
+    }
+
+    fn close(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.close(cx).map_err(Into::into)
+    }
+
+    fn flush_all(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.flush_all(cx).map_err(Into::into)
+    }
+}
+
+// orbits of this node connect through the in-memory transport
+fn is_local(endpoint: &ConnectedPoint) -> bool {
+    endpoint
+        .get_remote_address()
+        .iter()
+        .any(|p| matches!(p, Protocol::Memory(_)))
+}
+
+// configured listen addresses, or `address` and `port` for older configs
+fn listen_addrs(config: &config::Relay) -> Result<Vec<Multiaddr>> {
+    if !config.listen.is_empty() {
+        return Ok(config.listen.clone());
+    };
+    Ok(vec![match config.address.parse::<IpAddr>()? {
+        IpAddr::V4(ip) => multiaddr!(Ip4(ip), Tcp(config.port)),
+        IpAddr::V6(ip) => multiaddr!(Ip6(ip), Tcp(config.port)),
+    }])
+}
+
+// wildcard listen addresses can't be dialed, so they are only advertised when configured as external
+fn external_addrs(config: &config::Relay) -> Result<Vec<Multiaddr>> {
+    if !config.external.is_empty() {
+        return Ok(config.external.clone());
+    };
+    Ok(listen_addrs(config)?
+        .into_iter()
+        .filter(|addr| {
+            !addr.iter().any(|p| match p {
+                Protocol::Ip4(ip) => ip.is_unspecified(),
+                Protocol::Ip6(ip) => ip.is_unspecified(),
+                _ => false,
+            })
+        })
+        .collect())
+}
+
+fn advertise(external: &[Multiaddr], relays: &[Multiaddr], id: PeerId) -> Vec<Multiaddr> {
+    external
+        .iter()
+        .map(|addr| addr.clone().with(Protocol::P2p(id.into())))
+        .chain(relays.iter().cloned())
+        .collect()
+}
+
+/// Internal and advertised addresses of the relay a node with this ID would run, for loading
+/// orbits while the node is stopped without binding the relay's ports.
+pub fn offline_addrs(config: &config::Relay, id: PeerId) -> Result<(Multiaddr, Vec<Multiaddr>)> {
+    Ok((
+        multiaddr!(Memory(config.port)),
+        advertise(&external_addrs(config)?, &config.relays, id),
+    ))
+}
+
 impl RelayNode {
-    pub fn new(port: u16, key: Keypair) -> Result<Self> {
+    pub fn new(config: &config::Relay, path: PathBuf, key: Keypair) -> Result<Self> {
+        let port = config.port;
         let local_public_key = key.public();
         let id = local_public_key.into_peer_id();
+        let stats = Arc::new(RelayStats::default());
         let base = MemoryTransport.or_transport(DnsConfig::system(TcpConfig::new().nodelay(true))?);
-        let (t, r) = new_transport_and_behaviour(Default::default(), base);
+        let (t, r) = new_transport_and_behaviour(RelayConfig::default(), base);
 
+        let limits = Arc::new(Limits::new(&config.limits, stats.clone()));
         let transport = t
             .upgrade(Version::V1)
             .authenticate(
@@ -45,32 +364,126 @@ impl RelayNode {
                 MplexConfig::new(),
             ))
             .timeout(Duration::from_secs(5))
+            .map(move |(peer, muxer), endpoint| {
+                // circuits requested by this node's orbits are not limited
+                if is_local(&endpoint) {
+                    return (peer, StreamMuxerBox::new(muxer));
+                };
+                let limits = limits.clone();
+                (
+                    peer,
+                    StreamMuxerBox::new(Relayed {
+                        inner: muxer,
+                        peer,
+                        limits,
+                    }),
+                )
+            })
             .boxed();
 
-        let relay_tcp_addr = multiaddr!(Ip4([127, 0, 0, 1]), Tcp(port));
         let relay_mem_addr = multiaddr!(Memory(port));
-        let mut swarm = Swarm::new(transport, r, id);
+        let mut swarm = SwarmBuilder::new(transport, r, id).build();
 
-        tracing::debug!(
-            "opened relay: {} at {}, {}",
-            id,
-            relay_mem_addr,
-            relay_tcp_addr
-        );
-
-        swarm.listen_on(relay_tcp_addr)?;
+        for addr in listen_addrs(config)? {
+            tracing::debug!("opened relay: {} at {}", id, addr);
+            swarm.listen_on(addr)?;
+        }
         swarm.listen_on(relay_mem_addr)?;
 
-        let task = spawn(swarm.for_each_concurrent(None, |_| async move {}));
-        Ok(Self { port, task, id })
+        if let Some(r) = config
+            .relays
+            .iter()
+            .find(|r| !matches!(r.iter().last(), Some(Protocol::P2p(_))))
+        {
+            return Err(anyhow!("Relay address {} has no peer ID", r));
+        };
+        let external = external_addrs(config)?;
+        if external.is_empty() {
+            tracing::warn!("relay has no external address, set relay.external to advertise one");
+        };
+
+        let (hosts_only, task_stats) = (config.limits.hosts, stats.clone());
+        let task = spawn(async move {
+            while let Some(event) = swarm.next().await {
+                match event {
+                    SwarmEvent::ConnectionEstablished {
+                        peer_id, endpoint, ..
+                    } => {
+                        task_stats.connections.fetch_add(1, Ordering::Relaxed);
+                        if hosts_only && !is_local(&endpoint) {
+                            // cached, and refreshed whenever the orbits hosted here change
+                            let allowed = match hosted_peers(&path).await {
+                                Ok(peers) => peers.contains(&peer_id),
+                                Err(e) => {
+                                    tracing::error!("failed to list orbit hosts: {}", e);
+                                    false
+                                }
+                            };
+                            if !allowed {
+                                tracing::info!(
+                                    "refusing to relay for {}, not an orbit host",
+                                    peer_id
+                                );
+                                task_stats.denied.fetch_add(1, Ordering::Relaxed);
+                                let _ = swarm.disconnect_peer_id(peer_id);
+                                continue;
+                            }
+                        };
+                        tracing::debug!(
+                            "relay connection from {} at {}",
+                            peer_id,
+                            endpoint.get_remote_address()
+                        );
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                        task_stats.connections.fetch_sub(1, Ordering::Relaxed);
+                        tracing::debug!("relay connection to {} closed: {:?}", peer_id, cause);
+                    }
+                    SwarmEvent::IncomingConnectionError {
+                        send_back_addr,
+                        error,
+                        ..
+                    } => {
+                        tracing::debug!(
+                            "relay connection from {} failed: {}",
+                            send_back_addr,
+                            error
+                        );
+                    }
+                    _ => (),
+                }
+            }
+        });
+        Ok(Self {
+            port,
+            task,
+            id,
+            external,
+            relays: config.relays.clone(),
+            stats,
+        })
     }
 
     pub fn internal(&self) -> Multiaddr {
         multiaddr!(Memory(self.port))
     }
 
-    pub fn external(&self) -> Multiaddr {
-        multiaddr!(Ip4([127, 0, 0, 1]), Tcp(self.port))
+    /// The first advertised address of the relay, if any.
+    pub fn external(&self) -> Option<Multiaddr> {
+        self.external.first().cloned()
+    }
+
+    pub fn external_addrs(&self) -> &[Multiaddr] {
+        &self.external
+    }
+
+    /// Addresses of this relay and the other configured relays, as advertised for orbit hosts.
+    pub fn advertised(&self) -> Vec<Multiaddr> {
+        advertise(&self.external, &self.relays, self.id)
+    }
+
+    pub fn stats(&self) -> &RelayStats {
+        &self.stats
     }
 }
 
@@ -85,7 +498,8 @@ mod test {
     use super::*;
     use crate::ipfs::Ipfs;
     use ipfs_embed::{generate_keypair, Config, ToLibp2p};
-    use libp2p::core::multiaddr::{multiaddr, Protocol};
+    use libp2p::core::multiaddr::multiaddr;
+    use rocket::futures::{future::poll_fn, task::noop_waker_ref};
     use std::path::Path;
     use tempdir::TempDir;
 
@@ -99,8 +513,15 @@ mod test {
     #[tokio::test(flavor = "multi_thread")]
     async fn relay() -> Result<()> {
         crate::tracing_try_init();
-        let relay = RelayNode::new(10000, generate_keypair().to_keypair())?;
         let tmp = TempDir::new("test")?;
+        let relay = RelayNode::new(
+            &config::Relay {
+                port: 10000,
+                ..Default::default()
+            },
+            tmp.path().to_path_buf(),
+            generate_keypair().to_keypair(),
+        )?;
 
         let alice = Ipfs::new(get_cfg(tmp.path().join("alice"))).await?;
         let bob = Ipfs::new(get_cfg(tmp.path().join("bob"))).await?;
@@ -116,6 +537,7 @@ mod test {
             &alice.local_peer_id(),
             relay
                 .external()
+                .unwrap()
                 .with(Protocol::P2p(relay.id.clone().into()))
                 .with(Protocol::P2pCircuit)
                 .with(Protocol::P2p(alice.local_peer_id().into())),
@@ -128,4 +550,84 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn addresses() -> Result<()> {
+        let legacy = config::Relay::default();
+        assert_eq!(
+            listen_addrs(&legacy)?,
+            vec!["/ip4/127.0.0.1/tcp/8081".parse()?]
+        );
+        assert_eq!(external_addrs(&legacy)?, listen_addrs(&legacy)?);
+
+        let wildcard = config::Relay {
+            listen: vec![
+                "/ip4/0.0.0.0/tcp/8081".parse()?,
+                "/ip6/::/tcp/8081".parse()?,
+            ],
+            ..Default::default()
+        };
+        assert!(external_addrs(&wildcard)?.is_empty());
+
+        let advertised = config::Relay {
+            external: vec!["/dns4/relay.example.com/tcp/8081".parse()?],
+            ..wildcard
+        };
+        assert_eq!(external_addrs(&advertised)?, advertised.external);
+        Ok(())
+    }
+
+    #[test]
+    fn circuit_limits() -> Result<()> {
+        let stats = Arc::new(RelayStats::default());
+        let limits = Limits::new(
+            &config::RelayLimits {
+                circuits: Some(1),
+                total: Some(2),
+                bytes: Some(16),
+                ..Default::default()
+            },
+            stats.clone(),
+        );
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        assert!(limits.open(&alice));
+        assert!(!limits.open(&alice));
+        assert!(limits.open(&bob));
+        assert!(!limits.open(&carol));
+        limits.close(&alice);
+        assert!(limits.open(&carol));
+        assert_eq!(stats.circuits.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.denied.load(Ordering::Relaxed), 2);
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut meter = Meter::new(&limits);
+        meter.check(&mut cx, &limits)?;
+        meter.count(16, &limits);
+        assert!(meter.check(&mut cx, &limits).is_err());
+        assert!(meter.check(&mut cx, &limits).is_err());
+        assert_eq!(stats.bytes.load(Ordering::Relaxed), 16);
+        assert_eq!(stats.denied.load(Ordering::Relaxed), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_circuits() -> Result<()> {
+        let limits = Limits::new(
+            &config::RelayLimits {
+                duration: Some(1),
+                ..Default::default()
+            },
+            Arc::new(RelayStats::default()),
+        );
+        let mut meter = Meter::new(&limits);
+        // nothing is read or written, the expiry alone wakes the circuit up
+        let closed = poll_fn(|cx| match meter.check(cx, &limits) {
+            Ok(()) => Poll::Pending,
+            Err(e) => Poll::Ready(e),
+        });
+        tokio::time::timeout(Duration::from_secs(5), closed).await?;
+        assert_eq!(limits.stats.denied.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
 }
@@ -0,0 +1,218 @@
+//! Resolution of `kepler://` URIs to the hosts of their orbit and the content they address.
+//!
+//! A URI names its orbit either by ID, `kepler://<orbit id>`, or by the orbit method identifier
+//! the ID is hashed from, `kepler://tz;address=tz1...`. It may be followed by a CID,
+//! `kepler://<orbit>/<cid>`, or an S3 key, `kepler://<orbit>/s3/<key>`.
+
+use crate::config::{self, ExternalApis};
+use crate::orbit::{get_metadata, load_orbit, verify_oid};
+use crate::relay::RelayNode;
+use anyhow::Result;
+use ipfs_embed::{Multiaddr, PeerId};
+use libipld::cid::{
+    multibase::Base,
+    multihash::{Code, MultihashDigest},
+    Cid,
+};
+use rocket::{
+    http::{Header, Status},
+    response::{self, Responder, Response},
+    serde::json::Json,
+    Request, State,
+};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::{collections::HashMap as Map, path::PathBuf, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Orbit,
+    Content(Cid),
+    Key(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeplerUri {
+    pub orbit: Cid,
+    // the orbit method identifier, when the URI wasn't written with the orbit ID
+    pub method: Option<String>,
+    pub target: Target,
+}
+
+/// The ID of the orbit described by an orbit method identifier, e.g. `tz;address=tz1...`.
+pub fn orbit_id(method: &str) -> Result<Cid> {
+    let oid = Cid::new_v1(0x55, Code::Blake2b256.digest(method.as_bytes()));
+    // checks that the identifier names a method and has well-formed parameters
+    verify_oid(&oid, method)?;
+    Ok(oid)
+}
+
+impl FromStr for KeplerUri {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = s
+            .strip_prefix("kepler://")
+            .ok_or_else(|| anyhow!("Not a kepler URI"))?;
+        let (id, path) = match rest.split_once('/') {
+            Some((id, path)) => (id, path),
+            None => (rest, ""),
+        };
+        let (orbit, method) = match Cid::from_str(id) {
+            Ok(oid) => (oid, None),
+            Err(_) => (orbit_id(id)?, Some(id.to_string())),
+        };
+        let target = match (path, path.strip_prefix("s3/")) {
+            ("", _) => Target::Orbit,
+            (_, Some(key)) if !key.is_empty() => {
+                Target::Key(urlencoding::decode(key)?.into_owned())
+            }
+            (cid, _) => Target::Content(cid.parse()?),
+        };
+        Ok(Self {
+            orbit,
+            method,
+            target,
+        })
+    }
+}
+
+impl KeplerUri {
+    /// Path of the addressed content in the HTTP API of a host.
+    pub fn path(&self) -> Result<String> {
+        let orbit = self.orbit.to_string_of_base(Base::Base58Btc)?;
+        Ok(match &self.target {
+            Target::Orbit => format!("/{}", orbit),
+            Target::Content(cid) => {
+                format!("/{}/{}", orbit, cid.to_string_of_base(Base::Base58Btc)?)
+            }
+            Target::Key(key) => format!(
+                "/{}/s3/{}",
+                orbit,
+                key.split('/')
+                    .map(urlencoding::encode)
+                    .collect::<Vec<_>>()
+                    .join("/")
+            ),
+        })
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone)]
+pub struct Resolution {
+    #[serde_as(as = "DisplayFromStr")]
+    pub orbit: Cid,
+    #[serde_as(as = "Map<DisplayFromStr, _>")]
+    pub hosts: Map<PeerId, Vec<Multiaddr>>,
+    // whether the orbit is hosted on this node
+    pub local: bool,
+    pub path: String,
+}
+
+/// Finds the hosts of a URI's orbit, from the manifest of the orbit if it is hosted on this node
+/// or else from its method identifier.
+pub async fn resolve(
+    uri: &KeplerUri,
+    path: PathBuf,
+    relay: (PeerId, Multiaddr),
+    chains: &ExternalApis,
+) -> Result<Resolution> {
+    let (hosts, local) = match (load_orbit(uri.orbit, path, relay).await?, &uri.method) {
+        (Some(orbit), _) => (orbit.hosts.clone(), true),
+        (None, Some(method)) => (get_metadata(&uri.orbit, method, chains).await?.hosts, false),
+        (None, None) => {
+            return Err(anyhow!(
+                "Orbit {} is not hosted here, its method identifier is needed",
+                uri.orbit
+            ))
+        }
+    };
+    Ok(Resolution {
+        orbit: uri.orbit,
+        hosts,
+        local,
+        path: uri.path()?,
+    })
+}
+
+pub enum Resolved {
+    // content served by this node, requested again at its path
+    Local(String),
+    Remote(Json<Resolution>),
+}
+
+impl<'r> Responder<'r, 'static> for Resolved {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Self::Local(path) => Response::build()
+                .status(Status::TemporaryRedirect)
+                .header(Header::new("Location", path))
+                .ok(),
+            Self::Remote(resolution) => resolution.respond_to(req),
+        }
+    }
+}
+
+// the URI is percent-encoded as a whole, including the encoded values of a method identifier
+#[get("/resolve?<uri>")]
+pub async fn resolve_uri(
+    uri: String,
+    config: &State<config::Config>,
+    relay: &State<RelayNode>,
+) -> Result<Resolved, (Status, String)> {
+    let uri: KeplerUri = uri
+        .parse()
+        .map_err(|e: anyhow::Error| (Status::BadRequest, e.to_string()))?;
+    let resolution = resolve(
+        &uri,
+        config.database.path.clone(),
+        (relay.id, relay.internal()),
+        &config.chains,
+    )
+    .await
+    .map_err(|e| (Status::NotFound, e.to_string()))?;
+    Ok(match resolution.local {
+        true => Resolved::Local(resolution.path),
+        false => Resolved::Remote(Json(resolution)),
+    })
+}
+
+#[test]
+async fn uris() -> Result<()> {
+    let method = r#"did;did=did%3Akey%3Az6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom;hosts=12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly%3A%2Fip4%2F127.0.0.1%2Ftcp%2F8081%2Fp2p%2F12D3KooWG4GKKKocGcX9pfdcdQncaLM73mY4X6TwB6tT48g1ijTY%2Fp2p-circuit%2Fp2p%2F12D3KooWNmUKqU9EhKKyWdHTyZud8Yj3HWFyf7wSdAe6JudGg4Ly;vm=z6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom"#;
+    let oid = "zCT5htkeCSu7WefuBKYUidQJkRgEvEGZQrFVqYS6ZJVM6zwLCRcF";
+    let cid = "uAYAEHiB0uGRNPXEMdA9L-lXR2MKIZzKlgW1z6Ug4fSv3LRSPfQ";
+
+    let hashed: KeplerUri = format!("kepler://{}", oid).parse()?;
+    assert_eq!(hashed.orbit, oid.parse()?);
+    assert_eq!(hashed.target, Target::Orbit);
+    assert_eq!(hashed.path()?, format!("/{}", oid));
+
+    let raw: KeplerUri = format!("kepler://{}/{}", method, cid).parse()?;
+    assert_eq!(raw.orbit, hashed.orbit);
+    assert_eq!(raw.method.as_deref(), Some(method));
+    assert_eq!(raw.target, Target::Content(cid.parse()?));
+
+    let key: KeplerUri = format!("kepler://{}/s3/photos/a%20cat.jpg", oid).parse()?;
+    assert_eq!(key.target, Target::Key("photos/a cat.jpg".into()));
+    assert_eq!(key.path()?, format!("/{}/s3/photos/a%20cat.jpg", oid));
+
+    assert!(format!("https://{}", oid).parse::<KeplerUri>().is_err());
+    assert!("kepler://tz;address".parse::<KeplerUri>().is_err());
+
+    // without a method identifier, only orbits hosted here can be resolved
+    let tmp = tempdir::TempDir::new("resolver")?;
+    let relay = (PeerId::random(), "/ip4/127.0.0.1/tcp/8081".parse()?);
+    assert!(resolve(
+        &hashed,
+        tmp.path().into(),
+        relay.clone(),
+        &Default::default()
+    )
+    .await
+    .is_err());
+    let remote = resolve(&raw, tmp.path().into(), relay, &Default::default()).await?;
+    assert!(!remote.local);
+    assert_eq!(remote.hosts.len(), 1);
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use crate::s3::{ObjectBuilder, Service, Store};
+use anyhow::Result;
+use libipld::cid::Cid;
+use ssi::did::DIDURL;
+use std::collections::BTreeMap;
+
+/// Revocations of delegation IDs published to an orbit.
+///
+/// Revocations are kept in their own store so they are replicated between the orbit's hosts
+/// the same way as the S3 index, without showing up in S3 listings.
+#[derive(Clone)]
+pub struct Revocations(pub Service);
+
+impl Revocations {
+    pub fn start(store: Store) -> Result<Self> {
+        Ok(Self(Service::start(store)?))
+    }
+
+    fn key(id: &str, revoker: &DIDURL) -> Vec<u8> {
+        format!("{}/{}", id, revoker).into_bytes()
+    }
+
+    pub async fn revoke(&self, id: &str, revoker: &DIDURL) -> Result<()> {
+        let rm: Vec<(Vec<u8>, Option<(u64, Cid)>)> = vec![];
+        self.0
+            .write(
+                vec![(
+                    ObjectBuilder::new(Self::key(id, revoker), BTreeMap::new()),
+                    id.as_bytes(),
+                )],
+                rm,
+            )
+            .await
+    }
+
+    // a revocation only takes effect if it was published by someone with authority over the delegation
+    pub fn is_revoked<'a>(
+        &self,
+        id: &str,
+        revokers: impl IntoIterator<Item = &'a DIDURL>,
+    ) -> Result<bool> {
+        for revoker in revokers {
+            if self.0.get(Self::key(id, revoker))?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
@@ -1,23 +1,38 @@
 use anyhow::Result;
-use ipfs_embed::{generate_keypair, multiaddr::Protocol, Keypair, PeerId, ToLibp2p};
+use chrono::Utc;
+use ipfs_embed::{multiaddr::Protocol, PeerId};
+use libipld::multibase::Base;
 use rocket::{
     data::{Data, ToByteUnit},
     form::Form,
-    http::Status,
+    futures::Stream,
+    http::{ContentType, Status},
+    response::stream::ByteStream,
     serde::json::Json,
     State,
 };
-use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+use std::path::PathBuf;
 
-use crate::allow_list::OrbitAllowList;
+use crate::allow_list::{AllowList, OrbitAllowList};
+use crate::audit::{refused_creation, AuditQuery, AuditReport};
 use crate::auth::{
-    CreateAuthWrapper, DelAuthWrapper, GetAuthWrapper, ListAuthWrapper, PutAuthWrapper,
+    Action, AdminAuth, AuditAuthWrapper, AuthorizationToken, CreateAuthWrapper, DelAuthWrapper,
+    DeleteOrbitAuthWrapper, GetAuthWrapper, ListAuthWrapper, PutAuthWrapper, RevokeAuthWrapper,
+    SnapshotAuthWrapper, UpdateAuthWrapper,
 };
+use crate::car;
 use crate::cas::{CidWrap, ContentAddressedStorage};
 use crate::codec::{PutContent, SupportedCodecs};
 use crate::config;
-use crate::orbit::{create_orbit, get_metadata, load_orbit, Orbit};
+use crate::encryption;
+use crate::host_keys::{HostKeys, PendingKey};
+use crate::orbit::{
+    check_creation_policy, create_orbit, get_metadata, hosted_orbits, load_orbit, lock_creation,
+    AuthTokens, ControlProof, Orbit,
+};
+use crate::peers::OrbitPeers;
 use crate::relay::RelayNode;
+use crate::s3_routes::Metadata;
 
 // TODO need to check for every relevant endpoint that the orbit ID in the URL matches the one in the auth token
 async fn uri_listing(orbit: Orbit) -> Result<Json<Vec<String>>, (Status, String)> {
@@ -53,26 +68,6 @@ pub async fn list_content(
     uri_listing(orbit.0).await
 }
 
-#[get("/<orbit_id>", rank = 2)]
-pub async fn list_content_no_auth(
-    orbit_id: CidWrap,
-    config: &State<config::Config>,
-    relay: &State<RelayNode>,
-) -> Result<Json<Vec<String>>, (Status, String)> {
-    let orbit = match load_orbit(
-        orbit_id.0,
-        config.database.path.clone(),
-        (relay.id, relay.internal()),
-    )
-    .await
-    {
-        Ok(Some(o)) => o,
-        Ok(None) => return Err((Status::NotFound, anyhow!("Orbit not found").to_string())),
-        Err(e) => return Err((Status::InternalServerError, e.to_string())),
-    };
-    uri_listing(orbit).await
-}
-
 #[get("/<_orbit_id>/<hash>")]
 pub async fn get_content(
     _orbit_id: CidWrap,
@@ -86,38 +81,21 @@ pub async fn get_content(
     }
 }
 
-#[get("/<orbit_id>/<hash>", rank = 2)]
-pub async fn get_content_no_auth(
-    orbit_id: CidWrap,
-    hash: CidWrap,
-    config: &State<config::Config>,
-    relay: &State<RelayNode>,
-) -> Result<Option<Vec<u8>>, (Status, String)> {
-    let orbit = match load_orbit(
-        orbit_id.0,
-        config.database.path.clone(),
-        (relay.id, relay.internal()),
-    )
-    .await
-    {
-        Ok(Some(o)) => o,
-        Ok(None) => return Err((Status::NotFound, anyhow!("Orbit not found").to_string())),
-        Err(e) => return Err((Status::InternalServerError, e.to_string())),
-    };
-    match orbit.get(&hash.0).await {
-        Ok(Some(content)) => Ok(Some(content.to_vec())),
-        Ok(None) => Ok(None),
-        Err(_) => Ok(None),
-    }
-}
-
 #[put("/<_orbit_id>", data = "<data>")]
 pub async fn put_content(
     _orbit_id: CidWrap,
     data: Data<'_>,
     codec: SupportedCodecs,
     orbit: PutAuthWrapper,
+    md: Metadata,
 ) -> Result<String, (Status, String)> {
+    // as for S3 objects, content is opaque to hosts of encrypted orbits
+    if orbit.0.encrypted && !md.0.contains_key(encryption::SCHEME_HEADER) {
+        return Err((
+            Status::BadRequest,
+            "Orbit only accepts encrypted content".into(),
+        ));
+    };
     match orbit
         .0
         .put(
@@ -152,10 +130,21 @@ pub async fn put_content(
 pub async fn batch_put_content(
     _orbit_id: CidWrap,
     orbit: PutAuthWrapper,
+    md: Metadata,
     batch: Form<Vec<PutContent>>,
 ) -> Result<String, (Status, &'static str)> {
+    if orbit.0.encrypted && !md.0.contains_key(encryption::SCHEME_HEADER) {
+        return Err((Status::BadRequest, "Orbit only accepts encrypted content"));
+    };
+    let batch = batch.into_inner();
+    // nothing is written unless the token covers the whole batch
+    for content in batch.iter() {
+        if orbit.0.check_scope(&content.content).is_err() {
+            return Err((Status::Unauthorized, "Token not scoped to batch content"));
+        }
+    }
     let mut uris = Vec::<String>::new();
-    for content in batch.into_inner().into_iter() {
+    for content in batch.into_iter() {
         uris.push(
             orbit
                 .0
@@ -203,21 +192,38 @@ pub async fn open_orbit_allowlist(
     params_str: &str,
     config: &State<config::Config>,
     relay: &State<RelayNode>,
-    keys: &State<RwLock<HashMap<PeerId, Keypair>>>,
+    keys: &State<HostKeys>,
+    allowlist: Option<&State<AllowList>>,
 ) -> Result<(), (Status, &'static str)> {
     // no auth token, use allowlist
     match (
         get_metadata(&orbit_id.0, params_str, &config.chains).await,
-        config.orbits.allowlist.as_ref(),
+        allowlist,
     ) {
         (_, None) => Err((Status::InternalServerError, "Allowlist Not Configured")),
-        (Ok(md), Some(list)) => match list.is_allowed(&orbit_id.0).await {
-            Ok(_controllers) => {
+        (Ok(mut md), Some(list)) => match list.is_allowed(&orbit_id.0).await {
+            Ok(entry) => {
+                md.apply(entry);
+                // the allowlist has already been consulted, only the node policy remains
+                let _creating = lock_creation().await;
+                if let Err(e) = check_creation_policy::<AllowList>(
+                    &config.orbits.creation,
+                    &md,
+                    params_str,
+                    &config.database.path,
+                    None,
+                )
+                .await
+                {
+                    refused_creation(config, &md.id, None, &e).await;
+                    return Err((Status::Forbidden, "Orbit not allowed by node policy"));
+                };
                 create_orbit(
                     &md,
                     config.database.path.clone(),
                     &[],
                     (relay.id, relay.internal()),
+                    &relay.advertised(),
                     keys,
                 )
                 .await
@@ -230,27 +236,279 @@ pub async fn open_orbit_allowlist(
     }
 }
 
+#[post("/<_orbit_id>/revocations")]
+pub async fn revoke_delegations(
+    _orbit_id: CidWrap,
+    orbit: RevokeAuthWrapper,
+    token: AuthTokens,
+) -> Result<(), (Status, String)> {
+    let revoker = token
+        .invoker()
+        .map_err(|e| (Status::Unauthorized, e.to_string()))?;
+    if let Action::Revoke(ids) = token.action() {
+        for id in ids {
+            orbit
+                .0
+                .revocations
+                .revoke(id, &revoker)
+                .await
+                .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+        }
+    };
+    Ok(())
+}
+
+#[post("/<_orbit_id>/snapshots")]
+pub async fn create_snapshot(
+    _orbit_id: CidWrap,
+    orbit: SnapshotAuthWrapper,
+    token: AuthTokens,
+) -> Result<Json<Vec<String>>, (Status, String)> {
+    let label = match token.action() {
+        Action::Snapshot(label) => label,
+        _ => {
+            return Err((
+                Status::BadRequest,
+                "Token action not matching endpoint".into(),
+            ))
+        }
+    };
+    let heads = orbit
+        .0
+        .snapshots
+        .create(label, &orbit.0.service)
+        .await
+        .map_err(|e| (Status::BadRequest, e.to_string()))?;
+    Ok(Json(heads.iter().map(|h| h.to_string()).collect()))
+}
+
+#[get("/<_orbit_id>/audit?<from>&<to>&<actor>")]
+pub async fn audit_log(
+    _orbit_id: CidWrap,
+    orbit: AuditAuthWrapper,
+    token: AuthTokens,
+    from: Option<i64>,
+    to: Option<i64>,
+    actor: Option<String>,
+) -> Result<Json<AuditReport>, (Status, String)> {
+    let invoker = token
+        .invoker()
+        .map_err(|e| (Status::Unauthorized, e.to_string()))?;
+    if !orbit.0.controllers().iter().any(|c| c.did == invoker.did) {
+        return Err((
+            Status::Forbidden,
+            "Only controllers can read the audit log".into(),
+        ));
+    };
+    let log = orbit.0.audit();
+    let query = AuditQuery { from, to, actor };
+    Ok(Json(AuditReport {
+        entries: log
+            .query(&query)
+            .await
+            .map_err(|e| (Status::InternalServerError, e.to_string()))?,
+        broken: log
+            .verify()
+            .await
+            .map_err(|e| (Status::InternalServerError, e.to_string()))?,
+    }))
+}
+
+#[delete("/<_orbit_id>")]
+pub async fn delete_orbit(
+    _orbit_id: CidWrap,
+    orbit: DeleteOrbitAuthWrapper,
+    token: AuthTokens,
+    relay: &State<RelayNode>,
+) -> Result<(), (Status, String)> {
+    // leaving only affects this node, a deletion is also announced to the other hosts
+    let announce = match token.action() {
+        Action::Delete { leave: true } => None,
+        _ => Some(
+            ControlProof::from_token(&token).map_err(|e| (Status::BadRequest, e.to_string()))?,
+        ),
+    };
+    orbit
+        .0
+        .delete((relay.id, relay.internal()), announce)
+        .await
+        .map_err(|e| (Status::InternalServerError, e.to_string()))
+}
+
+#[post("/<_orbit_id>/manifest")]
+pub async fn update_manifest(
+    _orbit_id: CidWrap,
+    orbit: UpdateAuthWrapper,
+    token: AuthTokens,
+) -> Result<(), (Status, String)> {
+    let proof =
+        ControlProof::from_token(&token).map_err(|e| (Status::BadRequest, e.to_string()))?;
+    orbit
+        .0
+        .update_manifest(proof)
+        .await
+        .map_err(|e| (Status::BadRequest, e.to_string()))
+}
+
+#[get("/admin/export/<orbit_id>")]
+pub async fn export_orbit(
+    orbit_id: CidWrap,
+    _admin: AdminAuth,
+    config: &State<config::Config>,
+    relay: &State<RelayNode>,
+) -> Result<(ContentType, ByteStream<impl Stream<Item = Vec<u8>>>), (Status, String)> {
+    let orbit = load_orbit(
+        orbit_id.0,
+        config.database.path.clone(),
+        (relay.id, relay.internal()),
+    )
+    .await
+    .map_err(|e| (Status::InternalServerError, e.to_string()))?
+    .ok_or_else(|| (Status::NotFound, "No Orbit found".to_string()))?;
+    let archive = car::export(&orbit)
+        .await
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+    Ok((
+        ContentType::new("application", "vnd.ipld.car"),
+        ByteStream(archive),
+    ))
+}
+
+#[post("/admin/import", data = "<data>")]
+pub async fn import_orbit(
+    _admin: AdminAuth,
+    data: Data<'_>,
+    config: &State<config::Config>,
+    relay: &State<RelayNode>,
+    keys: &State<HostKeys>,
+) -> Result<String, (Status, String)> {
+    let orbit = car::import(
+        data.open(1u8.gigabytes()),
+        config.database.path.clone(),
+        (relay.id, relay.internal()),
+        &relay.advertised(),
+        keys,
+    )
+    .await
+    .map_err(|e| (Status::BadRequest, e.to_string()))?;
+    orbit
+        .id()
+        .to_string_of_base(Base::Base58Btc)
+        .map_err(|e| (Status::InternalServerError, e.to_string()))
+}
+
+#[get("/admin/peers")]
+pub async fn list_peers(
+    _admin: AdminAuth,
+    config: &State<config::Config>,
+    relay: &State<RelayNode>,
+) -> Result<Json<Vec<OrbitPeers>>, (Status, String)> {
+    let orbits = hosted_orbits(&config.database.path)
+        .await
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+    let mut peers = vec![];
+    for oid in orbits {
+        if let Some(orbit) = load_orbit(
+            oid,
+            config.database.path.clone(),
+            (relay.id, relay.internal()),
+        )
+        .await
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?
+        {
+            peers.push(orbit.peers());
+        }
+    }
+    Ok(Json(peers))
+}
+
+#[get("/admin/peers/<orbit_id>")]
+pub async fn orbit_peers(
+    orbit_id: CidWrap,
+    _admin: AdminAuth,
+    config: &State<config::Config>,
+    relay: &State<RelayNode>,
+) -> Result<Json<OrbitPeers>, (Status, String)> {
+    let orbit = load_orbit(
+        orbit_id.0,
+        config.database.path.clone(),
+        (relay.id, relay.internal()),
+    )
+    .await
+    .map_err(|e| (Status::InternalServerError, e.to_string()))?
+    .ok_or_else(|| (Status::NotFound, "No Orbit found".to_string()))?;
+    Ok(Json(orbit.peers()))
+}
+
 #[options("/<_s..>")]
 pub async fn cors(_s: PathBuf) -> () {
     ()
 }
 
 #[get("/peer/relay")]
-pub fn relay_addr(relay: &State<RelayNode>) -> String {
-    relay
+pub fn relay_addr(relay: &State<RelayNode>) -> Result<String, (Status, &'static str)> {
+    Ok(relay
         .external()
+        .ok_or((Status::NotFound, "Relay has no external address"))?
         .with(Protocol::P2p(relay.id.into()))
-        .to_string()
+        .to_string())
 }
 
 #[get("/peer/generate")]
 pub fn open_host_key(
-    s: &State<RwLock<HashMap<PeerId, Keypair>>>,
-) -> Result<String, (Status, &'static str)> {
-    let keypair = generate_keypair();
-    let id = keypair.to_peer_id();
-    s.write()
-        .map_err(|_| (Status::InternalServerError, "cant read keys"))?
-        .insert(id, keypair);
+    _admin: AdminAuth,
+    keys: &State<HostKeys>,
+) -> Result<String, (Status, String)> {
+    let key = keys
+        .generate(Utc::now().timestamp())
+        .map_err(|e| (Status::ServiceUnavailable, e.to_string()))?;
+    Ok(key.id.to_base58())
+}
+
+#[get("/admin/keys")]
+pub fn list_host_keys(
+    _admin: AdminAuth,
+    keys: &State<HostKeys>,
+) -> Result<Json<Vec<PendingKey>>, (Status, String)> {
+    keys.list(Utc::now().timestamp())
+        .map(Json)
+        .map_err(|e| (Status::InternalServerError, e.to_string()))
+}
+
+#[delete("/admin/keys/<peer_id>")]
+pub fn revoke_host_key(
+    peer_id: &str,
+    _admin: AdminAuth,
+    keys: &State<HostKeys>,
+) -> Result<(), (Status, String)> {
+    let id: PeerId = peer_id
+        .parse()
+        .map_err(|_| (Status::BadRequest, "Invalid peer ID".to_string()))?;
+    match keys.revoke(&id) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((Status::NotFound, "No pending key found".to_string())),
+        Err(e) => Err((Status::InternalServerError, e.to_string())),
+    }
+}
+
+#[post("/admin/rotate/<orbit_id>")]
+pub async fn rotate_host_key(
+    orbit_id: CidWrap,
+    _admin: AdminAuth,
+    config: &State<config::Config>,
+    relay: &State<RelayNode>,
+) -> Result<String, (Status, String)> {
+    let orbit = load_orbit(
+        orbit_id.0,
+        config.database.path.clone(),
+        (relay.id, relay.internal()),
+    )
+    .await
+    .map_err(|e| (Status::InternalServerError, e.to_string()))?
+    .ok_or_else(|| (Status::NotFound, "No Orbit found".to_string()))?;
+    let id = orbit
+        .rotate_key(&relay.advertised())
+        .await
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?;
     Ok(id.to_base58())
 }
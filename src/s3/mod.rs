@@ -9,9 +9,10 @@ mod entries;
 mod store;
 
 use super::ipfs::{Block, Ipfs};
+use super::metrics;
 
 pub use entries::{Object, ObjectBuilder, IpfsWriteStream, IpfsReadStream};
-pub use store::Store;
+pub use store::{Entry, Store};
 
 type TaskHandle = tokio::task::JoinHandle<()>;
 
@@ -30,13 +31,21 @@ impl Service {
     }
 
     pub fn start(config: Store) -> Result<Self> {
-        let events = config.ipfs.subscribe(&config.id)?.filter_map(|e| async {
-            match e {
-                GossipEvent::Message(p, d) => Some(match bincode::deserialize(&d) {
-                    Ok(m) => Ok((p, m)),
-                    Err(e) => Err(anyhow!(e)),
-                }),
-                _ => None,
+        let activity = config.activity.clone();
+        let events = config.ipfs.subscribe(&config.id)?.filter_map(move |e| {
+            match &e {
+                GossipEvent::Subscribed(p) => activity.subscribed(*p, true),
+                GossipEvent::Unsubscribed(p) => activity.subscribed(*p, false),
+                _ => (),
+            };
+            async move {
+                match e {
+                    GossipEvent::Message(p, d) => Some(match bincode::deserialize(&d) {
+                        Ok(m) => Ok((p, m)),
+                        Err(e) => Err(anyhow!(e)),
+                    }),
+                    _ => None,
+                }
             }
         });
         config.request_heads()?;
@@ -107,13 +116,18 @@ async fn kv_task(events: impl Stream<Item = Result<(PeerId, KVMessage)>> + Send,
             match ev {
                 Ok((p, KVMessage::Heads(heads))) => {
                     debug!("new heads from {}", p);
+                    metrics::gossip_message("heads");
+                    store.activity.heads_seen(p, &heads);
                     // sync heads
-                    if let Err(e) = store.try_merge_heads(heads.into_iter()).await {
+                    let merged = store.try_merge_heads(heads.into_iter()).await;
+                    metrics::delta_merge(merged.is_ok());
+                    if let Err(e) = merged {
                         error!("failed to merge heads {}", e);
                     };
                 }
                 Ok((p, KVMessage::StateReq)) => {
                     debug!("{} requests state", p);
+                    metrics::gossip_message("state");
                     // send heads
                     if let Err(e) = store.broadcast_heads() {
                         error!("failed to broadcast heads {}", e);
@@ -230,4 +244,73 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn index_at_heads() -> Result<(), anyhow::Error> {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("test_index_at")?;
+        let store = create_store("test_index_at", tmp.path().join("alice")).await?;
+        let key = "release/index.html";
+        let rm: Vec<(Vec<u8>, Option<(u64, Cid)>)> = vec![];
+
+        store
+            .write(
+                vec![(
+                    ObjectBuilder::new(key.as_bytes().to_vec(), BTreeMap::new()),
+                    "v1".as_bytes(),
+                )],
+                rm.clone(),
+            )
+            .await?;
+        let v1 = store.get(key)?.expect("v1 not found");
+        let heads = store.heads()?;
+
+        store
+            .write(
+                vec![(
+                    ObjectBuilder::new(key.as_bytes().to_vec(), BTreeMap::new()),
+                    "v2".as_bytes(),
+                )],
+                rm,
+            )
+            .await?;
+        let add: Vec<(&[u8], Cid)> = vec![];
+        store.index(add, vec![(key.as_bytes().to_vec(), None)])?;
+        assert_eq!(store.get(key)?, None);
+
+        // the old heads still resolve to the first version
+        let index = store.index_at(&heads)?;
+        assert_eq!(index.get(key.as_bytes()), Some(v1.to_block()?.cid()));
+        assert!(store.index_at(&store.heads()?)?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn usage() -> Result<(), anyhow::Error> {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("test_usage")?;
+        let store = create_store("test_usage", tmp.path().join("alice")).await?;
+        let rm: Vec<(Vec<u8>, Option<(u64, Cid)>)> = vec![];
+        let object = |key: &str| ObjectBuilder::new(key.as_bytes().to_vec(), BTreeMap::new());
+
+        store
+            .write(
+                vec![
+                    (object("a"), "1234".as_bytes()),
+                    (object("b"), "12".as_bytes()),
+                ],
+                rm.clone(),
+            )
+            .await?;
+        assert_eq!(store.usage()?, 6);
+
+        // overwriting a key only counts its latest object
+        store.write(vec![(object("a"), "1".as_bytes())], rm).await?;
+        assert_eq!(store.usage()?, 3);
+
+        let add: Vec<(&[u8], Cid)> = vec![];
+        store.index(add, vec![("b".as_bytes().to_vec(), None)])?;
+        assert_eq!(store.usage()?, 1);
+        Ok(())
+    }
 }
@@ -1,10 +1,12 @@
+use crate::metrics;
+use crate::peers::Activity;
 use crate::s3::{Object, ObjectBuilder, Service, IpfsWriteStream, IpfsReadStream};
 use anyhow::Result;
 use async_recursion::async_recursion;
 use libipld::{cid::Cid, DagCbor, cbor::DagCborCodec};
-use rocket::{futures::future::try_join_all, tokio::io::AsyncRead};
+use rocket::{futures::future::try_join_all, tokio::{io::AsyncRead, sync::Notify}};
 use sled::{Batch, Db, IVec, Tree};
-use std::{convert::{TryFrom, TryInto}, collections::BTreeMap};
+use std::{convert::{TryFrom, TryInto}, collections::{BTreeMap, HashSet}, sync::Arc};
 use tracing::{debug, error};
 use ipfs_embed::TempPin;
 
@@ -57,6 +59,15 @@ impl LinkedDelta {
     }
 }
 
+/// State of a key in a store's index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Entry {
+    Present(Cid),
+    // the object last written at the key has been removed
+    Deleted(Cid),
+    Absent,
+}
+
 #[derive(Clone)]
 pub struct Store {
     pub id: String,
@@ -64,9 +75,16 @@ pub struct Store {
     elements: Tree,
     tombs: Tree,
     priorities: Tree,
+    usage: Tree,
     heads: Heads,
+    pub activity: Activity,
+    // woken when a delta is applied, whether written here or merged from a peer
+    pub(crate) applied: Arc<Notify>,
 }
 
+// key of the running total of content bytes in the usage tree
+const USAGE: &[u8] = b"bytes";
+
 impl Store {
     pub fn new(id: String, ipfs: Ipfs, db: Db) -> Result<Self> {
         // map key to element cid
@@ -75,16 +93,104 @@ impl Store {
         let tombs = db.open_tree("tombs")?;
         // map key to current max priority for key
         let priorities = db.open_tree("priorities")?;
+        // content bytes of the objects currently in the index
+        let usage = db.open_tree("usage")?;
         // map current DAG head cids to their priority
         let heads = Heads::new(db)?;
-        Ok(Self {
+        let store = Self {
             id,
             ipfs,
             elements,
             tombs,
             priorities,
+            usage,
             heads,
-        })
+            activity: Activity::default(),
+            applied: Default::default(),
+        };
+        // stores written before usage was tracked are measured once, objects whose content
+        // never synced count as empty
+        if !store.usage.contains_key(USAGE)? {
+            let bytes = store
+                .entries()
+                .map(|r| -> Result<u64> { Ok(store.object_size(&r?.1).unwrap_or(0)) })
+                .sum::<Result<u64>>()?;
+            store.usage.insert(USAGE, &u642v(bytes))?;
+        };
+        metrics::storage(&store.id, store.usage()?);
+        Ok(store)
+    }
+
+    /// Bytes of content of the objects in the index, kept up to date as objects are added and
+    /// removed.
+    pub fn usage(&self) -> Result<u64> {
+        Ok(self.usage.get(USAGE)?.map(v2u64).transpose()?.unwrap_or(0))
+    }
+
+    // content bytes of an object, whose content list must be available locally
+    fn object_size(&self, cid: &Cid) -> Result<u64> {
+        let obj: Object = self.ipfs.get(cid)?.decode()?;
+        let content = self
+            .ipfs
+            .get(&obj.value)?
+            .decode::<DagCborCodec, Vec<(Cid, u32)>>()?;
+        Ok(content.iter().map(|(_, len)| *len as u64).sum())
+    }
+
+    fn add_usage(&self, added: u64, removed: u64) -> Result<()> {
+        if added == removed {
+            return Ok(());
+        };
+        let bytes = self.usage.update_and_fetch(USAGE, |v| {
+            let bytes = v.and_then(|v| v2u64(v).ok()).unwrap_or(0);
+            Some(u642v((bytes + added).saturating_sub(removed)).to_vec())
+        })?;
+        metrics::storage(&self.id, bytes.map(v2u64).transpose()?.unwrap_or(0));
+        Ok(())
+    }
+
+    pub fn heads(&self) -> Result<Vec<Cid>> {
+        Ok(self.heads.state()?.0)
+    }
+
+    /// Pins the DAGs of the given heads so they are kept for as long as the alias exists.
+    pub fn pin_heads(&self, alias: &[u8], heads: &[Cid]) -> Result<()> {
+        for head in heads {
+            self.ipfs.alias([alias, &head.to_bytes()].concat(), Some(head))?;
+        }
+        Ok(())
+    }
+
+    /// The index (key to object CID) as of the given heads, computed by walking their delta DAG
+    /// with the same ordering and tombstone rules as `apply`.
+    pub fn index_at(&self, heads: &[Cid]) -> Result<BTreeMap<Vec<u8>, Cid>> {
+        let mut seen = HashSet::new();
+        let mut stack = heads.to_vec();
+        let mut adds: Vec<(u64, Cid)> = vec![];
+        let mut tombs = HashSet::new();
+        while let Some(cid) = stack.pop() {
+            if !seen.insert(cid) {
+                continue;
+            };
+            let delta: LinkedDelta = self.ipfs.get(&cid)?.decode()?;
+            adds.extend(delta.delta.add.iter().map(|a| (delta.delta.priority, *a)));
+            tombs.extend(delta.delta.rmv);
+            stack.extend(delta.prev);
+        }
+
+        let mut elements: BTreeMap<Vec<u8>, (u64, Cid)> = BTreeMap::new();
+        for (priority, cid) in adds {
+            let obj: Object = self.ipfs.get(&cid)?.decode()?;
+            match elements.get(&obj.key) {
+                Some((p, c)) if *p > priority || (*p == priority && *c <= cid) => continue,
+                _ => elements.insert(obj.key, (priority, cid)),
+            };
+        }
+        Ok(elements
+            .into_iter()
+            .filter(|(_, (_, cid))| !tombs.contains(cid))
+            .map(|(key, (_, cid))| (key, cid))
+            .collect())
     }
     pub fn list(&self) -> impl DoubleEndedIterator<Item = Result<IVec>> + Send + Sync {
         self.elements
@@ -92,25 +198,40 @@ impl Store {
             .keys()
             .map(|r| r.map_err(|e| anyhow!(e)))
     }
-    pub fn get<N: AsRef<[u8]>>(&self, name: N) -> Result<Option<Object>> {
-        let key = name;
+    // like `list`, but with the object CIDs and without removed keys
+    pub fn entries(&self) -> impl Iterator<Item = Result<(IVec, Cid)>> + '_ {
+        self.elements
+            .iter()
+            .map(move |r| {
+                let (key, cid) = r?;
+                let cid = Cid::try_from(cid.as_ref())?;
+                Ok(match self.is_tombstoned(&key, &cid)? {
+                    true => None,
+                    false => Some((key, cid)),
+                })
+            })
+            .filter_map(|r: Result<Option<(IVec, Cid)>>| r.transpose())
+    }
+    /// Looks up the object at a key, telling apart keys deleted here from keys never written.
+    pub fn entry<N: AsRef<[u8]>>(&self, key: N) -> Result<Entry> {
         match self
             .elements
             .get(&key)?
             .map(|b| Cid::try_from(b.as_ref()))
             .transpose()?
         {
-            Some(cid) => {
-                if !self
-                    .tombs
-                    .contains_key([key.as_ref(), &cid.to_bytes()].concat())?
-                {
-                    Ok(Some(self.ipfs.get(&cid)?.decode()?))
-                } else {
-                    Ok(None)
-                }
-            }
-            None => Ok(None),
+            Some(cid) if self.is_tombstoned(&key, &cid)? => Ok(Entry::Deleted(cid)),
+            Some(cid) => Ok(Entry::Present(cid)),
+            None => Ok(Entry::Absent),
+        }
+    }
+    pub fn is_tombstoned<N: AsRef<[u8]>>(&self, key: N, cid: &Cid) -> Result<bool> {
+        Ok(self.tombs.contains_key(Self::get_key_id(key, cid))?)
+    }
+    pub fn get<N: AsRef<[u8]>>(&self, name: N) -> Result<Option<Object>> {
+        match self.entry(name)? {
+            Entry::Present(cid) => Ok(Some(self.ipfs.get(&cid)?.decode()?)),
+            _ => Ok(None),
         }
     }
 
@@ -118,10 +239,16 @@ impl Store {
         &self,
         key: N
     ) -> Result<Option<(BTreeMap<String, String>, IpfsReadStream)>> where N: AsRef<[u8]> {
-        let s3_obj = match self.get(key) {
-            Ok(Some(content)) => content,
-            _ => return Ok(None),
-        };
+        match self.get(key) {
+            Ok(Some(content)) => self.read_object(content),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_object(
+        &self,
+        s3_obj: Object
+    ) -> Result<Option<(BTreeMap<String, String>, IpfsReadStream)>> {
         match self.ipfs.get(&s3_obj.value)?.decode::<DagCborCodec, Vec<(Cid, u32)>>() {
             Ok(content) => Ok(Some((
                 s3_obj.metadata,
@@ -228,7 +355,15 @@ impl Store {
         // TODO update tables atomically with transaction
         // tombstone removed elements
         for (key, cid) in removes.into_iter() {
-            self.tombs.insert(Self::get_key_id(&key, &cid), &[])?;
+            let key_id = Self::get_key_id(&key, &cid);
+            if self.tombs.contains_key(&key_id)? {
+                continue;
+            };
+            self.tombs.insert(key_id, &[])?;
+            // only the object currently at the key counts towards the usage
+            if self.elements.get(&key)?.as_deref() == Some(&cid.to_bytes()[..]) {
+                self.add_usage(0, self.object_size(&cid)?)?;
+            };
         }
         for (key, cid) in adds.into_iter() {
             // ensure dont double add or remove
@@ -259,6 +394,11 @@ impl Store {
                 self.elements.insert(&key, cid.to_bytes())?;
                 self.priorities
                     .insert(&key, &u642v(delta.delta.priority))?;
+                let replaced = match curr {
+                    Some(c) if !self.is_tombstoned(&key, &c)? => self.object_size(&c)?,
+                    _ => 0,
+                };
+                self.add_usage(self.object_size(&cid)?, replaced)?;
             }
         }
         // find redundant heads and remove them
@@ -267,6 +407,7 @@ impl Store {
         self.heads.new_head(block.cid(), delta.prev.clone())?;
         self.ipfs.alias(block.cid().to_bytes(), Some(block.cid()))?;
         self.ipfs.insert(&block)?;
+        self.applied.notify_one();
 
         Ok(())
     }
@@ -277,6 +418,7 @@ impl Store {
         heads: impl Iterator<Item = Cid> + Send + 'async_recursion,
     ) -> Result<()> {
         try_join_all(heads.map(|head| async move {
+            let _want = self.activity.want(head);
             // fetch head block check block is an event
             let delta_block = self.ipfs.fetch(&head, self.ipfs.peers()).await?;
             let delta: LinkedDelta = delta_block.decode()?;
@@ -303,6 +445,8 @@ impl Store {
             let adds: Vec<(Vec<u8>, Cid)> =
                 try_join_all(delta.delta.add.iter().map(|c| async move {
                     let obj: Object = self.ipfs.fetch(&c, self.ipfs.peers()).await?.decode()?;
+                    // the content list sizes the object for the usage count
+                    self.ipfs.fetch(&obj.value, self.ipfs.peers()).await?;
                     Ok((obj.key, *c)) as Result<(Vec<u8>, Cid)>
                 }))
                 .await?;
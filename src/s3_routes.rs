@@ -3,17 +3,15 @@ use libipld::Cid;
 use rocket::{
     data::{Data, ToByteUnit},
     http::{Header, Status},
-    request::{FromRequest, Outcome, Request},
+    request::{FromParam, FromRequest, Outcome, Request},
     response::{self, Responder, Response},
     serde::json::Json,
-    State,
 };
 
 use crate::auth::{DelAuthWrapper, GetAuthWrapper, ListAuthWrapper, PutAuthWrapper};
 use crate::cas::{CidWrap};
-use crate::config;
-use crate::orbit::load_orbit;
-use crate::relay::RelayNode;
+use crate::encryption;
+use crate::orbit::Orbit;
 use crate::s3::{ObjectBuilder, IpfsReadStream};
 use std::{collections::BTreeMap, path::PathBuf};
 
@@ -61,35 +59,15 @@ impl<'r> Responder<'r, 'static> for S3Response {
     }
 }
 
-#[get("/<orbit_id>/s3", rank = 8)]
-pub async fn list_content_no_auth(
-    orbit_id: CidWrap,
-    config: &State<config::Config>,
-    relay: &State<RelayNode>,
-) -> Result<Json<Vec<String>>, (Status, String)> {
-    let orbit = match load_orbit(
-        orbit_id.0,
-        config.database.path.clone(),
-        (relay.id, relay.internal()),
-    )
-    .await
-    {
-        Ok(Some(o)) => o,
-        Ok(None) => return Err((Status::NotFound, anyhow!("Orbit not found").to_string())),
-        Err(e) => return Err((Status::InternalServerError, e.to_string())),
-    };
-    Ok(Json(
-        orbit
-            .service
-            .list()
-            .filter_map(|r| {
-                // filter out any non-utf8 keys
-                r.map(|v| std::str::from_utf8(v.as_ref()).ok().map(|s| s.to_string()))
-                    .transpose()
-            })
-            .collect::<Result<Vec<String>>>()
-            .map_err(|e| (Status::InternalServerError, e.to_string()))?,
-    ))
+async fn list_keys(orbit: &Orbit) -> Result<Vec<String>, (Status, String)> {
+    Ok(orbit
+        .entries()
+        .await
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?
+        .into_iter()
+        // filter out any non-utf8 keys
+        .filter_map(|(k, _)| String::from_utf8(k).ok())
+        .collect())
 }
 
 #[get("/<_orbit_id>/s3")]
@@ -97,19 +75,7 @@ pub async fn list_content(
     _orbit_id: CidWrap,
     orbit: ListAuthWrapper,
 ) -> Result<Json<Vec<String>>, (Status, String)> {
-    Ok(Json(
-        orbit
-            .0
-            .service
-            .list()
-            .filter_map(|r| {
-                // filter out any non-utf8 keys
-                r.map(|v| std::str::from_utf8(v.as_ref()).ok().map(|s| s.to_string()))
-                    .transpose()
-            })
-            .collect::<Result<Vec<String>>>()
-            .map_err(|e| (Status::InternalServerError, e.to_string()))?,
-    ))
+    Ok(Json(list_keys(&orbit.0).await?))
 }
 
 #[head("/<_orbit_id>/s3/<key..>")]
@@ -122,37 +88,8 @@ pub async fn get_metadata(
         Some(k) => k,
         _ => return Err((Status::BadRequest, "Key parsing failed".into())),
     };
-    match orbit.0.service.get(k) {
-        Ok(Some(content)) => Ok(Some(Metadata(content.metadata))),
-        Err(e) => Err((Status::InternalServerError, e.to_string())),
-        Ok(None) => Ok(None),
-    }
-}
-
-#[head("/<orbit_id>/s3/<key..>")]
-pub async fn get_metadata_no_auth(
-    orbit_id: CidWrap,
-    key: PathBuf,
-    config: &State<config::Config>,
-    relay: &State<RelayNode>,
-) -> Result<Option<Metadata>, (Status, String)> {
-    let k = match key.to_str() {
-        Some(k) => k,
-        _ => return Err((Status::BadRequest, "Key parsing failed".into())),
-    };
-    let orbit = match load_orbit(
-        orbit_id.0,
-        config.database.path.clone(),
-        (relay.id, relay.internal()),
-    )
-    .await
-    {
-        Ok(Some(o)) => o,
-        Ok(None) => return Err((Status::NotFound, anyhow!("Orbit not found").to_string())),
-        Err(e) => return Err((Status::InternalServerError, e.to_string())),
-    };
-    match orbit.service.get(k) {
-        Ok(Some(content)) => Ok(Some(Metadata(content.metadata))),
+    match orbit.0.resolve(k.as_bytes()).await {
+        Ok(Some(content)) => Ok(Some(Metadata(content.object.metadata))),
         Err(e) => Err((Status::InternalServerError, e.to_string())),
         Ok(None) => Ok(None),
     }
@@ -168,41 +105,51 @@ pub async fn get_content(
         Some(k) => k,
         _ => return Err((Status::BadRequest, "Key parsing failed".into())),
     };
-    match orbit.0.service.read(k) {
+    match orbit.0.read_key(k.as_bytes()).await {
         Ok(Some((md, r))) => Ok(Some(S3Response::new(Metadata(md), r))),
         _ => Ok(None),
     }
 }
 
-#[get("/<orbit_id>/s3/<key..>")]
-pub async fn get_content_no_auth(
-    orbit_id: CidWrap,
+/// Path segment addressing the S3 store at a snapshot, `s3@<label>`.
+pub struct SnapshotRef(pub String);
+
+impl<'a> FromParam<'a> for SnapshotRef {
+    type Error = &'a str;
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        match param.strip_prefix("s3@") {
+            Some(label) if !label.is_empty() => Ok(Self(label.into())),
+            _ => Err(param),
+        }
+    }
+}
+
+fn read_snapshot(
+    orbit: &Orbit,
+    snapshot: &SnapshotRef,
     key: PathBuf,
-    config: &State<config::Config>,
-    relay: &State<RelayNode>,
 ) -> Result<Option<S3Response>, (Status, String)> {
     let k = match key.to_str() {
         Some(k) => k,
         _ => return Err((Status::BadRequest, "Key parsing failed".into())),
     };
-    let orbit = match load_orbit(
-        orbit_id.0,
-        config.database.path.clone(),
-        (relay.id, relay.internal()),
-    )
-    .await
-    {
-        Ok(Some(o)) => o,
-        Ok(None) => return Err((Status::NotFound, anyhow!("Orbit not found").to_string())),
-        Err(e) => return Err((Status::InternalServerError, e.to_string())),
-    };
-
-    match orbit.service.read(k) {
+    match orbit.snapshots.read(&snapshot.0, &orbit.service, k) {
         Ok(Some((md, r))) => Ok(Some(S3Response::new(Metadata(md), r))),
-        _ => return Ok(None),
+        Ok(None) => Ok(None),
+        Err(e) => Err((Status::InternalServerError, e.to_string())),
     }
 }
 
+#[get("/<_orbit_id>/<snapshot>/<key..>", rank = 3)]
+pub async fn get_snapshot_content(
+    _orbit_id: CidWrap,
+    snapshot: SnapshotRef,
+    orbit: GetAuthWrapper,
+    key: PathBuf,
+) -> Result<Option<S3Response>, (Status, String)> {
+    read_snapshot(&orbit.0, &snapshot, key)
+}
+
 #[put("/<_orbit_id>/s3/<key..>", data = "<data>")]
 pub async fn put_content(
     _orbit_id: CidWrap,
@@ -215,6 +162,13 @@ pub async fn put_content(
         Some(k) => k,
         _ => return Err((Status::BadRequest, "Key parsing failed".into())),
     };
+    // content is opaque to hosts of encrypted orbits, only its declared scheme is checked
+    if orbit.0.encrypted && !md.0.contains_key(encryption::SCHEME_HEADER) {
+        return Err((
+            Status::BadRequest,
+            "Orbit only accepts encrypted objects".into(),
+        ));
+    };
     let rm: Vec<(Vec<u8>, Option<(u64, Cid)>)> = vec![];
 
     orbit
@@ -238,10 +192,9 @@ pub async fn delete_content(
         Some(k) => k,
         _ => return Err((Status::BadRequest, "Key parsing failed".into())),
     };
-    let add: Vec<(&[u8], Cid)> = vec![];
     Ok(orbit
         .0
-        .service
-        .index(add, vec![(k, None)])
+        .remove_key(k.as_bytes())
+        .await
         .map_err(|_| (Status::InternalServerError, "Failed to delete content"))?)
 }
@@ -0,0 +1,153 @@
+use crate::{
+    orbit::AbortOnDrop,
+    s3::{IpfsReadStream, ObjectBuilder, Service, Store},
+};
+use anyhow::Result;
+use libipld::cid::Cid;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+pub(crate) type Index = Arc<BTreeMap<Vec<u8>, Cid>>;
+
+/// Named sets of heads of an orbit's S3 store.
+///
+/// Snapshots are kept in their own store so they are replicated between the orbit's hosts like
+/// revocations. A label is never reassigned, which lets the index at a snapshot be cached.
+#[derive(Clone)]
+pub struct Snapshots {
+    pub service: Service,
+    cache: Arc<Mutex<HashMap<String, Index>>>,
+    pins: Arc<AbortOnDrop<()>>,
+}
+
+impl Snapshots {
+    pub fn start(store: Store) -> Result<Self> {
+        let service = Service::start(store)?;
+        // snapshots merged from other hosts are pinned like the ones created on this host
+        let (applied, store) = (service.store.applied.clone(), service.store.clone());
+        let pins = Arc::new(AbortOnDrop::new(tokio::spawn(async move {
+            loop {
+                applied.notified().await;
+                if let Err(e) = Self::pin_all(&store) {
+                    tracing::warn!("failed to pin snapshots: {}", e);
+                }
+            }
+        })));
+        Ok(Self {
+            service,
+            cache: Default::default(),
+            pins,
+        })
+    }
+
+    fn pin_alias(label: &str) -> Vec<u8> {
+        format!("snapshot/{}/", label).into_bytes()
+    }
+
+    // the stores of an orbit share its block store, so the snapshots store can pin the S3 store's
+    // heads
+    fn pin_all(store: &Store) -> Result<()> {
+        for label in store.list() {
+            let label = String::from_utf8(label?.to_vec())?;
+            if let Some(heads) = Self::heads_in(store, &label)? {
+                store.pin_heads(&Self::pin_alias(&label), &heads)?;
+            };
+        }
+        Ok(())
+    }
+
+    /// Records the current heads of `store` under `label` and pins everything reachable from them.
+    pub async fn create(&self, label: &str, store: &Store) -> Result<Vec<Cid>> {
+        if label.is_empty() || label.contains('/') {
+            return Err(anyhow!("Invalid snapshot label"));
+        };
+        if self.service.get(label)?.is_some() {
+            return Err(anyhow!("Snapshot {} already exists", label));
+        };
+        let heads = store.heads()?;
+        if heads.is_empty() {
+            return Err(anyhow!("Nothing to snapshot"));
+        };
+        store.pin_heads(&Self::pin_alias(label), &heads)?;
+
+        let md: BTreeMap<String, String> = [(
+            "heads".to_string(),
+            heads
+                .iter()
+                .map(|h| h.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+        )]
+        .iter()
+        .cloned()
+        .collect();
+        let rm: Vec<(Vec<u8>, Option<(u64, Cid)>)> = vec![];
+        self.service
+            .write(
+                vec![(
+                    ObjectBuilder::new(label.as_bytes().to_vec(), md),
+                    label.as_bytes(),
+                )],
+                rm,
+            )
+            .await?;
+        Ok(heads)
+    }
+
+    pub fn heads(&self, label: &str) -> Result<Option<Vec<Cid>>> {
+        Self::heads_in(&self.service.store, label)
+    }
+
+    fn heads_in(store: &Store, label: &str) -> Result<Option<Vec<Cid>>> {
+        match store.get(label)? {
+            Some(obj) => Ok(Some(
+                obj.metadata
+                    .get("heads")
+                    .ok_or_else(|| anyhow!("Snapshot {} has no heads", label))?
+                    .split(',')
+                    .map(|h| Ok(h.parse()?))
+                    .collect::<Result<Vec<Cid>>>()?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn index(&self, label: &str, store: &Store) -> Result<Option<Index>> {
+        if let Some(index) = self
+            .cache
+            .lock()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .get(label)
+        {
+            return Ok(Some(index.clone()));
+        };
+        let heads = match self.heads(label)? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        let index = Arc::new(store.index_at(&heads)?);
+        self.cache
+            .lock()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .insert(label.into(), index.clone());
+        Ok(Some(index))
+    }
+
+    /// Reads an object of `store` as it was when the snapshot was taken.
+    pub fn read<N: AsRef<[u8]>>(
+        &self,
+        label: &str,
+        store: &Store,
+        key: N,
+    ) -> Result<Option<(BTreeMap<String, String>, IpfsReadStream)>> {
+        match self
+            .index(label, store)?
+            .and_then(|index| index.get(key.as_ref()).copied())
+        {
+            Some(cid) => store.read_object(store.ipfs.get(&cid)?.decode()?),
+            None => Ok(None),
+        }
+    }
+}
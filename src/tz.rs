@@ -1,8 +1,10 @@
 use crate::{
     auth::{Action, AuthorizationPolicy, AuthorizationToken},
+    config,
     orbit::OrbitMetadata,
 };
 use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
 use libipld::{cid::multibase::Base, Cid};
 use nom::{
     branch::alt,
@@ -18,7 +20,7 @@ use ssi::{
     jws::verify_bytes,
     tzkey::{decode_tzsig, jwk_from_tezos_key},
 };
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
 
 #[derive(Debug, Clone)]
 pub struct TezosAuthorizationString {
@@ -125,8 +127,48 @@ fn parse_create(s: &str) -> IResult<&str, Action> {
     })
 }
 
+fn parse_revoke(s: &str) -> IResult<&str, Action> {
+    tuple((tag("REVOKE"), many1(space_delimit)))(s).map(|(rest, (_, ids))| {
+        (
+            rest,
+            Action::Revoke(ids.iter().map(|s| String::from(*s)).collect()),
+        )
+    })
+}
+
+fn parse_delete(s: &str) -> IResult<&str, Action> {
+    alt((tag("DELETE"), tag("LEAVE")))(s).map(|(rest, action)| {
+        (
+            rest,
+            Action::Delete {
+                leave: action == "LEAVE",
+            },
+        )
+    })
+}
+
+fn parse_snapshot(s: &str) -> IResult<&str, Action> {
+    tuple((tag("SNAPSHOT"), space_delimit))(s)
+        .map(|(rest, (_, label))| (rest, Action::Snapshot(label.into())))
+}
+
+fn parse_update(s: &str) -> IResult<&str, Action> {
+    tuple((tag("UPDATE"), space_delimit))(s)
+        .map(|(rest, (_, changes))| (rest, Action::Update(changes.into())))
+}
+
 fn parse_action(s: &str) -> IResult<&str, Action> {
-    alt((parse_get, parse_put, parse_del, parse_create, parse_list))(s)
+    alt((
+        parse_get,
+        parse_put,
+        parse_delete,
+        parse_del,
+        parse_create,
+        parse_list,
+        parse_revoke,
+        parse_snapshot,
+        parse_update,
+    ))(s)
 }
 
 fn serialize_action(action: &Action) -> Result<String> {
@@ -135,6 +177,11 @@ fn serialize_action(action: &Action) -> Result<String> {
         Action::Get(content) => serialize_content_action("GET", content),
         Action::Del(content) => serialize_content_action("DEL", content),
         Action::List => Ok("LIST".into()),
+        Action::Revoke(ids) => serialize_content_action("REVOKE", ids),
+        Action::Delete { leave: false } => Ok("DELETE".into()),
+        Action::Delete { leave: true } => Ok("LEAVE".into()),
+        Action::Snapshot(label) => Ok(["SNAPSHOT", label].join(" ")),
+        Action::Update(changes) => Ok(["UPDATE", changes].join(" ")),
         Action::Create {
             content,
             parameters,
@@ -159,6 +206,11 @@ impl TezosAuthorizationString {
         ))
     }
 
+    /// The signed message as sent in the Authorization header.
+    pub fn to_header(&self) -> Result<String> {
+        Ok([self.serialize()?, self.sig.clone()].join(" "))
+    }
+
     fn serialize_for_verification(&self) -> Result<Vec<u8>> {
         Ok(encode_string(&self.serialize()?))
     }
@@ -176,6 +228,70 @@ impl TezosAuthorizationString {
     }
 }
 
+impl TezosAuthorizationString {
+    /// Checks that the message is addressed to this node and was signed recently.
+    pub fn check_freshness(&self, auth: &config::Auth, now: DateTime<Utc>) -> Result<()> {
+        if let Some(domain) = &auth.domain {
+            if &self.domain != domain {
+                return Err(anyhow!(
+                    "Signed message is for domain {}, expected {}",
+                    &self.domain,
+                    domain
+                ));
+            }
+        };
+        let signed_at = self.signed_at()?;
+        if signed_at > now + Duration::seconds(auth.skew as i64) {
+            Err(anyhow!("Signed message timestamp is in the future"))
+        } else if now > signed_at + Duration::seconds(auth.lifetime as i64) {
+            Err(anyhow!("Signed message has expired"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn signed_at(&self) -> Result<DateTime<Utc>> {
+        Ok(DateTime::parse_from_rfc3339(&self.timestamp)
+            .map_err(|e| anyhow!("Invalid signed message timestamp: {}", e))?
+            .with_timezone(&Utc))
+    }
+}
+
+/// Signatures of write messages which have been used, kept until the messages expire.
+#[derive(Default)]
+pub struct NonceCache(Mutex<HashMap<String, DateTime<Utc>>>);
+
+impl NonceCache {
+    pub fn use_once(&self, sig: &str, expiry: DateTime<Utc>, now: DateTime<Utc>) -> Result<()> {
+        let mut used = self.0.lock().map_err(|e| anyhow!(e.to_string()))?;
+        used.retain(|_, exp| *exp >= now);
+        if used.contains_key(sig) {
+            return Err(anyhow!("Signed message has already been used"));
+        };
+        used.insert(sig.into(), expiry);
+        Ok(())
+    }
+}
+
+/// Rejects stale, misaddressed or replayed signed messages according to the node's config.
+pub fn check_replay(
+    token: &TezosAuthorizationString,
+    auth: &config::Auth,
+    nonces: &NonceCache,
+) -> Result<()> {
+    let now = Utc::now();
+    token.check_freshness(auth, now)?;
+    match token.action {
+        Action::Get(_) | Action::List => Ok(()),
+        _ if auth.nonces => nonces.use_once(
+            &token.sig,
+            token.signed_at()? + Duration::seconds(auth.lifetime as i64),
+            now,
+        ),
+        _ => Ok(()),
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for TezosAuthorizationString {
     type Error = anyhow::Error;
@@ -198,6 +314,13 @@ impl AuthorizationToken for TezosAuthorizationString {
     fn target_orbit(&self) -> &Cid {
         &self.orbit
     }
+    fn invoker(&self) -> Result<DIDURL> {
+        Ok(DIDURL {
+            did: format!("did:pkh:tz:{}", &self.pkh),
+            fragment: Some("TezosMethod2021".to_string()),
+            ..Default::default()
+        })
+    }
 }
 
 fn encode_string(s: &str) -> Vec<u8> {
@@ -234,11 +357,7 @@ impl core::fmt::Display for TezosAuthorizationString {
 #[rocket::async_trait]
 impl AuthorizationPolicy<TezosAuthorizationString> for OrbitMetadata {
     async fn authorize(&self, auth_token: &TezosAuthorizationString) -> Result<()> {
-        let requester = DIDURL {
-            did: format!("did:pkh:tz:{}", &auth_token.pkh),
-            fragment: Some("TezosMethod2021".to_string()),
-            ..Default::default()
-        };
+        let requester = auth_token.invoker()?;
 
         if !self.controllers.contains(&requester) {
             Err(anyhow!("Requester not a controller of the orbit"))
@@ -262,6 +381,82 @@ async fn simple_parse() {
     let _: TezosAuthorizationString = auth_str.parse().unwrap();
 }
 
+#[test]
+async fn revoke_parse() {
+    let auth_str = "Tezos Signed Message: kepler.net 2021-01-14T15:16:04Z edpkurFSehqm2HhLP9sZ4ZRW5nLZgyWErW8wYxgEUPHCMCy6Hk1tbm tz1Y6SXe4J9DBVuGM3GnWC2jnmDkA6fBVyjg uAYAEHiB_A0nLzANfXNkW5WCju51Td_INJ6UacFK7qY6zejzKoA REVOKE uuid:bac4da68-eb75-446b-8f9f-87608cbf872b edsigtmZ5tgugBSKjBJgptkm523C9EtVWrBhLYtv9MTAE6qF6mii2mFapdQfcCMsVzRisgQ3Nx61qC9Ut3VigyEC1s19RLwgkog";
+    let tza: TezosAuthorizationString = auth_str.parse().unwrap();
+    match tza.action {
+        Action::Revoke(ids) => assert_eq!(ids, vec!["uuid:bac4da68-eb75-446b-8f9f-87608cbf872b"]),
+        _ => panic!("expected revoke action"),
+    }
+}
+
+#[test]
+async fn delete_parse() {
+    let auth_str = "Tezos Signed Message: kepler.net 2021-01-14T15:16:04Z edpkurFSehqm2HhLP9sZ4ZRW5nLZgyWErW8wYxgEUPHCMCy6Hk1tbm tz1Y6SXe4J9DBVuGM3GnWC2jnmDkA6fBVyjg uAYAEHiB_A0nLzANfXNkW5WCju51Td_INJ6UacFK7qY6zejzKoA LEAVE edsigtmZ5tgugBSKjBJgptkm523C9EtVWrBhLYtv9MTAE6qF6mii2mFapdQfcCMsVzRisgQ3Nx61qC9Ut3VigyEC1s19RLwgkog";
+    let tza: TezosAuthorizationString = auth_str.parse().unwrap();
+    assert!(matches!(tza.action, Action::Delete { leave: true }));
+    let reparsed: TezosAuthorizationString = tza.to_header().unwrap().parse().unwrap();
+    assert!(matches!(reparsed.action, Action::Delete { leave: true }));
+    assert_eq!(reparsed.sig, tza.sig);
+
+    let auth_str = auth_str.replace(" LEAVE ", " DELETE ");
+    let tza: TezosAuthorizationString = auth_str.parse().unwrap();
+    assert!(matches!(tza.action, Action::Delete { leave: false }));
+
+    let auth_str = auth_str.replace(" DELETE ", " SNAPSHOT v1.2.0 ");
+    let tza: TezosAuthorizationString = auth_str.parse().unwrap();
+    match tza.action {
+        Action::Snapshot(label) => assert_eq!(label, "v1.2.0"),
+        _ => panic!("expected snapshot action"),
+    }
+
+    let auth_str = auth_str.replace(" SNAPSHOT v1.2.0 ", " UPDATE public=true ");
+    let tza: TezosAuthorizationString = auth_str.parse().unwrap();
+    match &tza.action {
+        Action::Update(changes) => assert_eq!(changes, "public=true"),
+        _ => panic!("expected update action"),
+    }
+    let reparsed: TezosAuthorizationString = tza.to_header().unwrap().parse().unwrap();
+    assert!(matches!(reparsed.action, Action::Update(_)));
+}
+
+#[test]
+async fn freshness() {
+    let auth_str = "Tezos Signed Message: kepler.net 2021-01-14T15:16:04Z edpkurFSehqm2HhLP9sZ4ZRW5nLZgyWErW8wYxgEUPHCMCy6Hk1tbm tz1Y6SXe4J9DBVuGM3GnWC2jnmDkA6fBVyjg uAYAEHiB_A0nLzANfXNkW5WCju51Td_INJ6UacFK7qY6zejzKoA PUT uAYAEHiB0uGRNPXEMdA9L-lXR2MKIZzKlgW1z6Ug4fSv3LRSPfQ edsigtmZ5tgugBSKjBJgptkm523C9EtVWrBhLYtv9MTAE6qF6mii2mFapdQfcCMsVzRisgQ3Nx61qC9Ut3VigyEC1s19RLwgkog";
+    let tza: TezosAuthorizationString = auth_str.parse().unwrap();
+    let signed_at = tza.signed_at().unwrap();
+    let auth = config::Auth {
+        domain: Some("kepler.net".into()),
+        ..Default::default()
+    };
+
+    assert!(tza.check_freshness(&auth, signed_at).is_ok());
+    assert!(tza
+        .check_freshness(&auth, signed_at - Duration::seconds(auth.skew as i64 + 1))
+        .is_err());
+    assert!(tza
+        .check_freshness(
+            &auth,
+            signed_at + Duration::seconds(auth.lifetime as i64 + 1)
+        )
+        .is_err());
+    assert!(tza
+        .check_freshness(
+            &config::Auth {
+                domain: Some("other.net".into()),
+                ..Default::default()
+            },
+            signed_at
+        )
+        .is_err());
+
+    let nonces = NonceCache::default();
+    let expiry = signed_at + Duration::seconds(auth.lifetime as i64);
+    assert!(nonces.use_once(&tza.sig, expiry, signed_at).is_ok());
+    assert!(nonces.use_once(&tza.sig, expiry, signed_at).is_err());
+}
+
 #[test]
 #[should_panic]
 async fn simple_verify_fail() {
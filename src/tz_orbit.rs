@@ -80,6 +80,11 @@ pub async fn get_orbit_state(tzkt_api: &str, address: &str, id: Cid) -> Result<O
             .map(|(k, _)| Ok(DIDURL::from_str(&k)?))
             .collect::<Result<Vec<DIDURL>>>()?,
         revocations: vec![],
+        credential_policies: vec![],
+        quota: None,
+        geometry: None,
+        encrypted: false,
+        public: false,
     })
 }
 
@@ -98,6 +103,11 @@ pub async fn params_to_tz_orbit(
             read_delegators: vec![],
             write_delegators: vec![],
             revocations: vec![],
+            credential_policies: vec![],
+            quota: None,
+            geometry: None,
+            encrypted: false,
+            public: false,
             hosts: Map::new(),
         }),
         _ => Err(anyhow!("Missing address or contract")),
@@ -0,0 +1,454 @@
+use crate::{
+    auth::{
+        requested_action, scope_covers, scope_within, Action, AuthorizationPolicy,
+        AuthorizationToken,
+    },
+    cas::CidWrap,
+    config,
+    orbit::{Orbit, OrbitMetadata},
+    revocations::Revocations,
+};
+use anyhow::Result;
+use async_recursion::async_recursion;
+use chrono::Utc;
+use didkit::DID_METHODS;
+use libipld::cid::{
+    multihash::{Code, MultihashDigest},
+    Cid,
+};
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+};
+use serde::{Deserialize, Serialize};
+use ssi::{
+    did::DIDURL,
+    did_resolve::DIDResolver,
+    jwk::JWK,
+    jws::{decode_unverified, decode_verify},
+};
+use std::str::FromStr;
+
+// bound the work done verifying a single request
+const MAX_CHAIN_LENGTH: usize = 8;
+const MAX_PROOFS: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Capability {
+    pub with: String,
+    pub can: String,
+}
+
+impl Capability {
+    // orbit, scope and ability, where the whole orbit is the scope `*`
+    fn parts(&self) -> Option<(Cid, &str, &str)> {
+        let ability = self.can.strip_prefix("kepler/")?;
+        let resource = self.with.strip_prefix("kepler://")?;
+        let (oid, scope) = match resource.split_once('/') {
+            Some((oid, "")) | Some((oid, "*")) => (oid, "*"),
+            Some((oid, scope)) => (oid, scope),
+            None => (resource, "*"),
+        };
+        Some((Cid::from_str(oid).ok()?, scope, ability))
+    }
+
+    /// Resources are `kepler://<orbit-id>` for a whole orbit, or `kepler://<orbit-id>/<scope>`
+    /// for a key, key prefix ending with `*` or CID within it. Abilities are `kepler/<action>`.
+    pub fn grants(&self, orbit: &Cid, action: &Action) -> bool {
+        let name = match action {
+            Action::Put(_) => "put",
+            Action::Get(_) => "get",
+            Action::Del(_) => "del",
+            Action::List => "list",
+            _ => return false,
+        };
+        let (oid, scope, ability) = match self.parts() {
+            Some(p) => p,
+            None => return false,
+        };
+        if &oid != orbit || (ability != name && ability != "*") {
+            return false;
+        };
+        match scope {
+            "*" => true,
+            scope => {
+                !action.content().is_empty()
+                    && action.content().iter().all(|c| scope_covers(scope, c))
+            }
+        }
+    }
+
+    /// Whether a capability delegated from this one is attenuated to it.
+    pub fn covers(&self, other: &Capability) -> bool {
+        match (self.parts(), other.parts()) {
+            (Some((oid, scope, ability)), Some((o, s, a))) => {
+                oid == o && (ability == "*" || ability == a) && scope_within(scope, s)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Payload {
+    pub iss: String,
+    pub aud: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    pub exp: i64,
+    #[serde(default)]
+    pub att: Vec<Capability>,
+    #[serde(default)]
+    pub prf: Vec<String>,
+}
+
+impl Payload {
+    fn check_time(&self, now: i64) -> Result<()> {
+        if self.exp < now {
+            Err(anyhow!("UCAN has expired"))
+        } else if self.nbf.map(|nbf| nbf > now).unwrap_or(false) {
+            Err(anyhow!("UCAN is not yet valid"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// keys published in the issuer's DID document
+async fn issuer_keys(did: &str) -> Result<Vec<JWK>> {
+    let (_, doc, _) = DID_METHODS
+        .to_resolver()
+        .resolve(did, &Default::default())
+        .await;
+    let doc =
+        serde_json::to_value(doc.ok_or_else(|| anyhow!("Failed to resolve UCAN issuer {}", did))?)?;
+    Ok(doc["verificationMethod"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|vm| vm.get("publicKeyJwk"))
+        .filter_map(|jwk| serde_json::from_value(jwk.clone()).ok())
+        .collect())
+}
+
+async fn verify(jwt: &str) -> Result<Payload> {
+    let (_, payload) = decode_unverified(jwt)?;
+    let payload: Payload = serde_json::from_slice(&payload)?;
+    if issuer_keys(&payload.iss)
+        .await?
+        .iter()
+        .any(|key| decode_verify(jwt, key).is_ok())
+    {
+        Ok(payload)
+    } else {
+        Err(anyhow!("Invalid UCAN signature for {}", payload.iss))
+    }
+}
+
+#[derive(Clone)]
+pub struct UCANTokens {
+    pub jwt: String,
+    pub payload: Payload,
+    orbit: Cid,
+    action: Action,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UCANTokens {
+    type Error = anyhow::Error;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let jwt = match request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+        {
+            Some(jwt) => jwt,
+            None => return Outcome::Forward(()),
+        };
+        let payload = match decode_unverified(jwt)
+            .map_err(|e| anyhow!(e))
+            .and_then(|(_, p)| serde_json::from_slice(&p).map_err(|e| anyhow!(e)))
+        {
+            Ok(p) => p,
+            Err(e) => return Outcome::Failure((Status::Unauthorized, e)),
+        };
+        // UCANs carry capabilities rather than a single invocation, so the action is the request
+        match (request.param::<CidWrap>(0), requested_action(request)) {
+            (Some(Ok(orbit)), Some(action)) => Outcome::Success(Self {
+                jwt: jwt.into(),
+                payload,
+                orbit: orbit.0,
+                action,
+            }),
+            _ => Outcome::Failure((
+                Status::BadRequest,
+                anyhow!("Request cannot be authorized with a UCAN"),
+            )),
+        }
+    }
+}
+
+impl AuthorizationToken for UCANTokens {
+    fn action(&self) -> &Action {
+        &self.action
+    }
+    fn target_orbit(&self) -> &Cid {
+        &self.orbit
+    }
+    fn invoker(&self) -> Result<DIDURL> {
+        Ok(DIDURL {
+            did: self.payload.iss.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+/// UCANs are revoked by the CID of their encoded JWT, as a raw block.
+pub fn ucan_id(jwt: &str) -> String {
+    Cid::new_v1(0x55, Code::Blake3_256.digest(jwt.as_bytes())).to_string()
+}
+
+// a UCAN may be revoked by a controller or by its issuer, with any of its listed keys
+fn check_revoked(
+    md: &OrbitMetadata,
+    revocations: &Revocations,
+    jwt: &str,
+    iss: &str,
+) -> Result<()> {
+    let id = ucan_id(jwt);
+    let issuer = DIDURL {
+        did: iss.into(),
+        ..Default::default()
+    };
+    let issuer_vms = md
+        .controllers
+        .iter()
+        .chain(md.read_delegators.iter())
+        .chain(md.write_delegators.iter())
+        .filter(|vm| vm.did == iss);
+    if revocations.is_revoked(
+        &id,
+        md.controllers
+            .iter()
+            .chain(issuer_vms)
+            .chain(std::iter::once(&issuer)),
+    )? {
+        return Err(anyhow!("UCAN {} has been revoked", id));
+    };
+    Ok(())
+}
+
+fn is_authority(md: &OrbitMetadata, did: &str, action: &Action) -> bool {
+    let listed = |vms: &[DIDURL]| vms.iter().any(|vm| vm.did == did);
+    match action {
+        Action::List | Action::Get(_) => {
+            listed(&md.controllers) || listed(&md.read_delegators) || listed(&md.write_delegators)
+        }
+        Action::Put(_) | Action::Del(_) => listed(&md.controllers) || listed(&md.write_delegators),
+        _ => false,
+    }
+}
+
+// every link must grant the action within the capabilities of the link it was delegated by, and
+// the chain must end with a UCAN issued by an authority
+#[async_recursion]
+async fn check_chain(
+    md: &OrbitMetadata,
+    revocations: Option<&Revocations>,
+    jwt: &str,
+    delegated: Option<&Payload>,
+    action: &Action,
+    depth: usize,
+    proofs: &mut usize,
+) -> Result<()> {
+    if depth >= MAX_CHAIN_LENGTH {
+        return Err(anyhow!("UCAN proof chain too long"));
+    };
+    *proofs += 1;
+    if *proofs > MAX_PROOFS {
+        return Err(anyhow!("Too many UCAN proofs"));
+    };
+    let ucan = verify(jwt).await?;
+    ucan.check_time(Utc::now().timestamp())?;
+    if md.revocations.contains(&ucan_id(jwt)) {
+        return Err(anyhow!("UCAN {} has been revoked", ucan_id(jwt)));
+    };
+    if let Some(revocations) = revocations {
+        check_revoked(md, revocations, jwt, &ucan.iss)?;
+    };
+    let granted: Vec<&Capability> = ucan
+        .att
+        .iter()
+        .filter(|cap| cap.grants(&md.id, action))
+        .collect();
+    if granted.is_empty() {
+        return Err(anyhow!("UCAN from {} does not grant the action", ucan.iss));
+    };
+    if let Some(child) = delegated {
+        if ucan.aud != child.iss {
+            return Err(anyhow!("UCAN proof not addressed to {}", child.iss));
+        };
+        // the capabilities used by the delegated UCAN can't exceed this one's
+        if !child
+            .att
+            .iter()
+            .filter(|cap| cap.grants(&md.id, action))
+            .all(|cap| granted.iter().any(|g| g.covers(cap)))
+        {
+            return Err(anyhow!("UCAN from {} escalates its proof", child.iss));
+        };
+    };
+    if is_authority(md, &ucan.iss, action) {
+        return Ok(());
+    };
+    let mut err = anyhow!("UCAN issuer {} not authorized", ucan.iss);
+    for proof in ucan.prf.iter() {
+        match check_chain(
+            md,
+            revocations,
+            proof,
+            Some(&ucan),
+            action,
+            depth + 1,
+            proofs,
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => err = e,
+        }
+    }
+    Err(err)
+}
+
+impl UCANTokens {
+    /// The invoked UCAN is sent as-is with each request, so it must expire within the lifetime of
+    /// a signed message, and be addressed to the node when its domain is configured.
+    pub fn check_freshness(&self, auth: &config::Auth, now: i64) -> Result<()> {
+        if self.payload.exp > now + (auth.lifetime + auth.skew) as i64 {
+            return Err(anyhow!("UCAN is valid for longer than {}s", auth.lifetime));
+        };
+        match &auth.domain {
+            Some(domain) if self.payload.aud != format!("did:web:{}", domain) => {
+                Err(anyhow!("UCAN is not addressed to did:web:{}", domain))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl AuthorizationPolicy<UCANTokens> for OrbitMetadata {
+    async fn authorize(&self, auth_token: &UCANTokens) -> Result<()> {
+        check_chain(
+            self,
+            None,
+            &auth_token.jwt,
+            None,
+            &auth_token.action,
+            0,
+            &mut 0,
+        )
+        .await
+    }
+}
+
+#[rocket::async_trait]
+impl AuthorizationPolicy<UCANTokens> for Orbit {
+    async fn authorize(&self, auth_token: &UCANTokens) -> Result<()> {
+        check_chain(
+            self.metadata(),
+            Some(&self.revocations),
+            &auth_token.jwt,
+            None,
+            &auth_token.action,
+            0,
+            &mut 0,
+        )
+        .await
+    }
+}
+
+#[test]
+async fn capabilities() {
+    let orbit: Cid = "zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg"
+        .parse()
+        .unwrap();
+    let cap = |with: &str, can: &str| Capability {
+        with: with.into(),
+        can: can.into(),
+    };
+    let get = Action::Get(vec!["photos/cat.jpg".into()]);
+
+    let whole = cap(
+        "kepler://zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg",
+        "kepler/get",
+    );
+    assert!(whole.grants(&orbit, &get));
+    assert!(!whole.grants(&orbit, &Action::List));
+
+    let photos = cap(
+        "kepler://zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg/photos/*",
+        "kepler/*",
+    );
+    assert!(photos.grants(&orbit, &get));
+    assert!(!photos.grants(&orbit, &Action::Get(vec!["docs/cv.pdf".into()])));
+    assert!(!photos.grants(&orbit, &Action::List));
+
+    let other = cap(
+        "kepler://zCT5htkeCSu7WefuBKYUidQJkRgEvEGZQrFVqYS6ZJVM6zwLCRcF",
+        "kepler/get",
+    );
+    assert!(!other.grants(&orbit, &get));
+
+    // delegated capabilities must stay within the ones they were delegated from
+    assert!(photos.covers(&cap(
+        "kepler://zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg/photos/2021/*",
+        "kepler/get",
+    )));
+    assert!(!whole.covers(&photos));
+    assert!(whole.covers(&whole));
+    assert!(!photos.covers(&cap(
+        "kepler://zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg",
+        "kepler/get",
+    )));
+    assert!(!whole.covers(&other));
+}
+
+#[test]
+async fn freshness() {
+    let ucan = |exp: i64, aud: &str| UCANTokens {
+        jwt: String::new(),
+        payload: Payload {
+            iss: "did:key:z6MkqAhhDfRhP8eMWUtk3FjG2nMiXNUGNU5Evsnq89uKNdom".into(),
+            aud: aud.into(),
+            nbf: None,
+            exp,
+            att: vec![],
+            prf: vec![],
+        },
+        orbit: "zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg"
+            .parse()
+            .unwrap(),
+        action: Action::List,
+    };
+    let auth = config::Auth::default();
+    let now = 1_600_000_000;
+    assert!(ucan(now + 60, "did:web:kepler.example.com")
+        .check_freshness(&auth, now)
+        .is_ok());
+    assert!(ucan(now + 86400, "did:web:kepler.example.com")
+        .check_freshness(&auth, now)
+        .is_err());
+
+    let auth = config::Auth {
+        domain: Some("kepler.example.com".into()),
+        ..Default::default()
+    };
+    assert!(ucan(now + 60, "did:web:kepler.example.com")
+        .check_freshness(&auth, now)
+        .is_ok());
+    assert!(ucan(now + 60, "did:web:other.example.com")
+        .check_freshness(&auth, now)
+        .is_err());
+}
@@ -0,0 +1,354 @@
+use crate::{
+    auth::{requested_action, Action, AuthorizationPolicy, AuthorizationToken},
+    cas::CidWrap,
+    config,
+    orbit::OrbitMetadata,
+    tz::NonceCache,
+};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use didkit::DID_METHODS;
+use libipld::cid::Cid;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ssi::{
+    did::DIDURL,
+    vc::{Credential, LinkedDataProofOptions, Presentation, ProofPurpose},
+};
+use std::str::FromStr;
+
+/// A rule granting actions on an orbit to the holders of a kind of credential.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialPolicy {
+    #[serde(rename = "type")]
+    pub type_: String,
+    // accepted issuer DIDs, the orbit's controllers if empty
+    #[serde(default)]
+    pub issuers: Vec<String>,
+    // the credential subject must name this orbit in its `orbit` property
+    #[serde(default)]
+    pub orbit_member: bool,
+    // granted actions, as in the `capabilityAction` of a delegation
+    pub actions: Vec<String>,
+}
+
+fn values(v: &Value) -> Vec<&Value> {
+    match v {
+        Value::Array(a) => a.iter().collect(),
+        Value::Null => vec![],
+        v => vec![v],
+    }
+}
+
+fn did_of(vm: &str) -> &str {
+    vm.split('#').next().unwrap_or(vm)
+}
+
+fn parse_time(v: &Value) -> Option<DateTime<Utc>> {
+    v.as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+impl CredentialPolicy {
+    fn matches(&self, md: &OrbitMetadata, credential: &Value, holder: &str) -> bool {
+        let issuer = credential["issuer"]
+            .as_str()
+            .or_else(|| credential["issuer"]["id"].as_str());
+        values(&credential["type"])
+            .iter()
+            .any(|t| t.as_str() == Some(&self.type_))
+            && match issuer {
+                Some(issuer) if self.issuers.is_empty() => {
+                    md.controllers.iter().any(|c| c.did == issuer)
+                }
+                Some(issuer) => self.issuers.iter().any(|i| i == issuer),
+                None => false,
+            }
+            && values(&credential["credentialSubject"]).iter().any(|s| {
+                s["id"].as_str() == Some(holder)
+                    && (!self.orbit_member
+                        || matches!(
+                            s["orbit"].as_str().map(Cid::from_str),
+                            Some(Ok(ref o)) if o == md.id()
+                        ))
+            })
+    }
+}
+
+#[derive(Clone)]
+pub struct PresentationTokens {
+    pub presentation: Presentation,
+    orbit: Cid,
+    action: Action,
+    // the request, as `<method> <path>`, which the proofs must be challenged with
+    request: String,
+}
+
+impl PresentationTokens {
+    fn value(&self) -> Result<Value> {
+        Ok(serde_json::to_value(&self.presentation)?)
+    }
+
+    // the holder must have signed every proof of the presentation
+    fn holder(&self) -> Result<String> {
+        let vp = self.value()?;
+        let holder = vp["holder"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Presentation has no holder"))?;
+        let proofs = values(&vp["proof"]);
+        if proofs.is_empty()
+            || !proofs.iter().all(|p| {
+                p["verificationMethod"]
+                    .as_str()
+                    .map(|vm| did_of(vm) == holder)
+                    .unwrap_or(false)
+            })
+        {
+            return Err(anyhow!("Presentation not signed by its holder"));
+        };
+        Ok(holder.into())
+    }
+
+    /// Checks that the presentation is addressed to this node, was signed recently and for this
+    /// request.
+    pub fn check_freshness(&self, auth: &config::Auth, now: DateTime<Utc>) -> Result<()> {
+        for proof in values(&self.value()?["proof"]) {
+            if let Some(domain) = &auth.domain {
+                if proof["domain"].as_str() != Some(domain) {
+                    return Err(anyhow!("Presentation is not for domain {}", domain));
+                }
+            };
+            if proof["challenge"].as_str() != Some(&self.request) {
+                return Err(anyhow!("Presentation is not for {}", self.request));
+            };
+            match parse_time(&proof["created"]) {
+                Some(created) if created > now + Duration::seconds(auth.skew as i64) => {
+                    return Err(anyhow!("Presentation is dated in the future"))
+                }
+                Some(created) if now > created + Duration::seconds(auth.lifetime as i64) => {
+                    return Err(anyhow!("Presentation has expired"))
+                }
+                Some(_) => (),
+                None => return Err(anyhow!("Presentation proof has no creation time")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects stale or misaddressed presentations, and presentations for writes which have
+    /// already been used.
+    pub fn check_replay(
+        &self,
+        auth: &config::Auth,
+        nonces: &NonceCache,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        self.check_freshness(auth, now)?;
+        // the challenge doesn't cover the body, so a write can't be authorized twice whatever
+        // `auth.nonces` is set to
+        if matches!(self.action, Action::Get(_) | Action::List) {
+            return Ok(());
+        };
+        for proof in values(&self.value()?["proof"]) {
+            let sig = proof["jws"]
+                .as_str()
+                .or_else(|| proof["proofValue"].as_str())
+                .ok_or_else(|| anyhow!("Presentation proof has no signature"))?;
+            let expiry = parse_time(&proof["created"])
+                .ok_or_else(|| anyhow!("Presentation proof has no creation time"))?
+                + Duration::seconds(auth.lifetime as i64);
+            nonces.use_once(sig, expiry, now)?;
+        }
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PresentationTokens {
+    type Error = anyhow::Error;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let presentation = match request
+            .headers()
+            .get_one("x-kepler-presentation")
+            .map(|b64| {
+                base64::decode_config(b64, base64::URL_SAFE)
+                    .map_err(|e| anyhow!(e))
+                    .and_then(|s| serde_json::from_slice(&s).map_err(|e| anyhow!(e)))
+            }) {
+            Some(Ok(p)) => p,
+            Some(Err(e)) => return Outcome::Failure((Status::Unauthorized, e)),
+            None => return Outcome::Forward(()),
+        };
+        // presentations carry credentials rather than a single invocation, so the action is the request
+        match (request.param::<CidWrap>(0), requested_action(request)) {
+            (Some(Ok(orbit)), Some(action)) => Outcome::Success(Self {
+                presentation,
+                orbit: orbit.0,
+                action,
+                request: format!("{} {}", request.method(), request.uri().path()),
+            }),
+            _ => Outcome::Failure((
+                Status::BadRequest,
+                anyhow!("Request cannot be authorized with a presentation"),
+            )),
+        }
+    }
+}
+
+impl AuthorizationToken for PresentationTokens {
+    fn action(&self) -> &Action {
+        &self.action
+    }
+    fn target_orbit(&self) -> &Cid {
+        &self.orbit
+    }
+    fn invoker(&self) -> Result<DIDURL> {
+        Ok(DIDURL {
+            did: self.holder()?,
+            ..Default::default()
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl AuthorizationPolicy<PresentationTokens> for OrbitMetadata {
+    async fn authorize(&self, auth_token: &PresentationTokens) -> Result<()> {
+        let action = match auth_token.action {
+            Action::Put(_) => "put",
+            Action::Get(_) => "get",
+            Action::Del(_) => "del",
+            Action::List => "list",
+            _ => return Err(anyhow!("Invalid Action")),
+        };
+        let holder = auth_token.holder()?;
+        let res = auth_token
+            .presentation
+            .verify(
+                Some(LinkedDataProofOptions {
+                    proof_purpose: Some(ProofPurpose::Authentication),
+                    ..Default::default()
+                }),
+                DID_METHODS.to_resolver(),
+            )
+            .await;
+        if let Some(e) = res.errors.first() {
+            return Err(anyhow!(e.clone()));
+        };
+        let vp = auth_token.value()?;
+        for credential in values(&vp["verifiableCredential"]) {
+            if !self.credential_policies.iter().any(|p| {
+                p.actions.iter().any(|a| a == action) && p.matches(self, credential, &holder)
+            }) {
+                continue;
+            };
+            if parse_time(&credential["expirationDate"])
+                .map(|exp| exp < Utc::now())
+                .unwrap_or(false)
+            {
+                continue;
+            };
+            let vc: Credential = serde_json::from_value(credential.clone())?;
+            if vc
+                .verify(None, DID_METHODS.to_resolver())
+                .await
+                .errors
+                .is_empty()
+            {
+                return Ok(());
+            };
+        }
+        Err(anyhow!(
+            "No presented credential satisfies the orbit policies"
+        ))
+    }
+}
+
+#[test]
+async fn policy_matching() {
+    let md = OrbitMetadata {
+        id: "zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg"
+            .parse()
+            .unwrap(),
+        controllers: vec![DIDURL::from_str("did:example:controller#key").unwrap()],
+        read_delegators: vec![],
+        write_delegators: vec![],
+        hosts: Default::default(),
+        revocations: vec![],
+        credential_policies: vec![],
+        quota: None,
+        geometry: None,
+        encrypted: false,
+        public: false,
+    };
+    let member = CredentialPolicy {
+        type_: "OrbitMember".into(),
+        issuers: vec![],
+        orbit_member: true,
+        actions: vec!["get".into()],
+    };
+    let credential = serde_json::json!({
+        "type": ["VerifiableCredential", "OrbitMember"],
+        "issuer": "did:example:controller",
+        "credentialSubject": {
+            "id": "did:example:holder",
+            "orbit": "zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg"
+        }
+    });
+    assert!(member.matches(&md, &credential, "did:example:holder"));
+    assert!(!member.matches(&md, &credential, "did:example:someone-else"));
+
+    let other_issuer = CredentialPolicy {
+        issuers: vec!["did:example:issuer".into()],
+        ..member
+    };
+    assert!(!other_issuer.matches(&md, &credential, "did:example:holder"));
+}
+
+#[test]
+async fn request_binding() {
+    let now = Utc::now();
+    let token = |challenge: &str, action: Action| PresentationTokens {
+        presentation: serde_json::from_value(serde_json::json!({
+            "@context": ["https://www.w3.org/2018/credentials/v1"],
+            "type": ["VerifiablePresentation"],
+            "holder": "did:example:holder",
+            "proof": {
+                "type": "Ed25519Signature2018",
+                "proofPurpose": "authentication",
+                "verificationMethod": "did:example:holder#key",
+                "created": now.to_rfc3339(),
+                "challenge": challenge,
+                "jws": "eyJhbGciOiJFZERTQSJ9..c2ln"
+            }
+        }))
+        .unwrap(),
+        orbit: "zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg"
+            .parse()
+            .unwrap(),
+        action,
+        request: "PUT /zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg/s3/cat.jpg".into(),
+    };
+    let put = Action::Put(vec!["cat.jpg".into()]);
+    // nonces are off, and still used for presentations
+    let auth = config::Auth::default();
+    let nonces = NonceCache::default();
+
+    let other = token(
+        "PUT /zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg/s3/dog.jpg",
+        put.clone(),
+    );
+    assert!(other.check_freshness(&auth, now).is_err());
+
+    let bound = token(
+        "PUT /zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg/s3/cat.jpg",
+        put,
+    );
+    assert!(bound.check_replay(&auth, &nonces, now).is_ok());
+    assert!(bound.check_replay(&auth, &nonces, now).is_err());
+}
@@ -1,6 +1,7 @@
 use crate::{
-    auth::{Action, AuthorizationPolicy, AuthorizationToken},
-    orbit::OrbitMetadata,
+    auth::{scope_within, Action, AuthorizationPolicy, AuthorizationToken},
+    orbit::{Orbit, OrbitMetadata},
+    revocations::Revocations,
 };
 use anyhow::Result;
 use chrono::{DateTime, Utc};
@@ -25,6 +26,9 @@ use std::{collections::HashMap as Map, str::FromStr};
 pub struct DelProps {
     pub capability_action: Vec<String>,
     pub expiration: Option<DateTime<Utc>>,
+    // keys, key prefixes ending with `*` or CIDs which the delegation is restricted to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<String>>,
     #[serde(flatten)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_fields: Option<Map<String, Value>>,
@@ -89,26 +93,199 @@ impl AuthorizationToken for ZCAPTokens {
     fn target_orbit(&self) -> &Cid {
         &self.invocation.property_set.invocation_target
     }
+    fn invoker(&self) -> Result<DIDURL> {
+        self.invocation
+            .proof
+            .as_ref()
+            .and_then(|proof| proof.verification_method.as_ref())
+            .ok_or_else(|| anyhow!("Missing invocation verification method"))
+            .and_then(|s| DIDURL::from_str(&s).map_err(|e| e.into()))
+    }
+}
+
+// bounds the work done verifying a single invocation
+const MAX_CHAIN_LENGTH: usize = 8;
+
+fn uri_string(uri: &URI) -> &str {
+    match uri {
+        URI::String(s) => s,
+    }
+}
+
+fn capability_chain(d: &KeplerDelegation) -> impl Iterator<Item = &Value> {
+    d.proof
+        .as_ref()
+        .and_then(|proof| proof.property_set.as_ref())
+        .and_then(|props| props.get("capabilityChain"))
+        .and_then(|chain| chain.as_array())
+        .into_iter()
+        .flatten()
+}
+
+// the parent delegation is embedded as the last entry of the capability chain
+fn parent_delegation(d: &KeplerDelegation) -> Result<Option<KeplerDelegation>> {
+    match capability_chain(d).last() {
+        Some(parent @ Value::Object(_)) => Ok(Some(serde_json::from_value(parent.clone())?)),
+        _ => Ok(None),
+    }
+}
+
+fn delegator(d: &KeplerDelegation) -> Result<DIDURL> {
+    d.proof
+        .as_ref()
+        .and_then(|proof| proof.verification_method.as_ref())
+        .ok_or_else(|| anyhow!("Missing delegation verification method"))
+        .and_then(|s| DIDURL::from_str(&s).map_err(|e| e.into()))
+}
+
+fn action_name(action: &Action) -> Result<&'static str> {
+    match action {
+        Action::List => Ok("list"),
+        Action::Put(_) => Ok("put"),
+        Action::Get(_) => Ok("get"),
+        Action::Del(_) => Ok("del"),
+        Action::Revoke(_) => Ok("revoke"),
+        Action::Delete { .. } => Ok("delete"),
+        Action::Snapshot(_) => Ok("snapshot"),
+        Action::Update(_) => Ok("update"),
+        Action::Create { .. } => Err(anyhow!("Invalid Action")),
+    }
+}
+
+// the root of a chain must delegate the invoked orbit itself
+fn check_root(root: &KeplerDelegation, orbit: &Cid) -> Result<()> {
+    match uri_string(&root.parent_capability)
+        .strip_prefix("kepler://")
+        .map(Cid::from_str)
+    {
+        Some(Ok(ref oid)) if oid == orbit => Ok(()),
+        _ => Err(anyhow!("Root delegation is not a capability of the orbit")),
+    }
+}
+
+fn check_attenuation(parent: &KeplerDelegation, child: &KeplerDelegation) -> Result<()> {
+    if uri_string(&child.parent_capability) != uri_string(&parent.id) {
+        return Err(anyhow!(
+            "Delegation does not refer to its parent capability"
+        ));
+    };
+    // a capability without an invoker can be invoked by anyone, but not delegated further
+    match parent.invoker {
+        Some(ref authorized_delegator)
+            if authorized_delegator == &URI::String(delegator(child)?.to_string()) => {}
+        _ => return Err(anyhow!("Delegator not authorized by parent capability")),
+    };
+    if !child
+        .property_set
+        .capability_action
+        .iter()
+        .all(|a| parent.property_set.capability_action.contains(a))
+    {
+        return Err(anyhow!("Delegated actions exceed parent capability"));
+    };
+    if let Some(ref parent_paths) = parent.property_set.paths {
+        match child.property_set.paths {
+            Some(ref paths)
+                if paths
+                    .iter()
+                    .all(|p| parent_paths.iter().any(|pp| scope_within(pp, p))) => {}
+            _ => return Err(anyhow!("Delegated paths exceed parent capability")),
+        }
+    };
+    match (
+        parent.property_set.expiration,
+        child.property_set.expiration,
+    ) {
+        (Some(p), Some(c)) if c > p => Err(anyhow!("Delegation outlives parent capability")),
+        (Some(_), None) => Err(anyhow!("Delegation outlives parent capability")),
+        _ => Ok(()),
+    }
+}
+
+// a delegator revokes delegations it signed by presenting them, without a delegation of the
+// `revoke` action
+fn revokes_own(chain: &[KeplerDelegation], ids: &[String], invoker: &DIDURL) -> bool {
+    !ids.is_empty()
+        && ids.iter().all(|id| {
+            chain.iter().any(|d| {
+                uri_string(&d.id) == id && delegator(d).map(|vm| &vm == invoker).unwrap_or(false)
+            })
+        })
+}
+
+impl ZCAPTokens {
+    /// The chain of the invoked delegation, starting from the root delegation.
+    pub fn delegations(&self) -> Result<Vec<KeplerDelegation>> {
+        let mut chain = Vec::new();
+        let mut next = self.delegation.clone();
+        while let Some(d) = next {
+            if chain.len() >= MAX_CHAIN_LENGTH {
+                return Err(anyhow!("Delegation chain too long"));
+            };
+            next = parent_delegation(&d)?;
+            chain.push(d);
+        }
+        chain.reverse();
+        if let Some(root) = chain.first() {
+            check_root(root, self.target_orbit())?;
+        };
+        Ok(chain)
+    }
+
+    /// IDs of every capability in the chain of the invoked delegation, with the delegator
+    /// which signed it when the delegation itself was presented.
+    pub fn delegation_ids(&self) -> Result<Vec<(String, Option<DIDURL>)>> {
+        let chain = self.delegations()?;
+        let mut ids = chain
+            .iter()
+            .map(|d| Ok((uri_string(&d.id).to_string(), Some(delegator(d)?))))
+            .collect::<Result<Vec<(String, Option<DIDURL>)>>>()?;
+        for id in chain
+            .iter()
+            .flat_map(|d| capability_chain(d).filter_map(|c| c.as_str()))
+        {
+            if !ids.iter().any(|(known, _)| known == id) {
+                ids.push((id.to_string(), None));
+            }
+        }
+        Ok(ids)
+    }
 }
 
 #[rocket::async_trait]
 impl AuthorizationPolicy<ZCAPTokens> for OrbitMetadata {
     async fn authorize(&self, auth_token: &ZCAPTokens) -> Result<()> {
-        let invoker_vm = auth_token
-            .invocation
-            .proof
-            .as_ref()
-            .and_then(|proof| proof.verification_method.as_ref())
-            .ok_or_else(|| anyhow!("Missing delegation verification method"))
-            .and_then(|s| DIDURL::from_str(&s).map_err(|e| e.into()))?;
-        let res = match &auth_token.delegation {
-            Some(d) => {
-                let delegator_vm = d
-                    .proof
-                    .as_ref()
-                    .and_then(|proof| proof.verification_method.as_ref())
-                    .ok_or_else(|| anyhow!("Missing delegation verification method"))
-                    .and_then(|s| DIDURL::from_str(&s).map_err(|e| e.into()))?;
+        let invoker_vm = auth_token.invoker()?;
+        if let Some((id, _)) = auth_token
+            .delegation_ids()?
+            .iter()
+            .find(|(id, _)| self.revocations.contains(id))
+        {
+            return Err(anyhow!("Delegation {} has been revoked", id));
+        };
+        let chain = auth_token.delegations()?;
+        if let Action::Revoke(ids) = auth_token.action() {
+            if !chain.is_empty() && revokes_own(&chain, ids, &invoker_vm) {
+                let mut res = auth_token
+                    .invocation
+                    .verify_signature(Default::default(), DID_METHODS.to_resolver())
+                    .await;
+                for d in chain.iter() {
+                    let mut link_res = d
+                        .verify(Default::default(), DID_METHODS.to_resolver())
+                        .await;
+                    res.append(&mut link_res);
+                }
+                return res
+                    .errors
+                    .first()
+                    .map(|e| Err(anyhow!(e.clone())))
+                    .unwrap_or(Ok(()));
+            }
+        };
+        let res = match (chain.first(), chain.last()) {
+            (Some(root), Some(leaf)) => {
+                let delegator_vm = delegator(root)?;
                 match auth_token.invocation.property_set.capability_action {
                     Action::List | Action::Get(_) => {
                         if !self.read_delegators.contains(&delegator_vm)
@@ -125,42 +302,65 @@ impl AuthorizationPolicy<ZCAPTokens> for OrbitMetadata {
                             return Err(anyhow!("Delegator not write-authorized"));
                         }
                     }
-                    _ => return Err(anyhow!("Invalid Action")),
+                    // changes to the orbit itself are only delegated by its controllers
+                    Action::Revoke(_)
+                    | Action::Delete { .. }
+                    | Action::Snapshot(_)
+                    | Action::Update(_) => {
+                        if !self.controllers.contains(&delegator_vm) {
+                            return Err(anyhow!("Delegator not authorized to change the orbit"));
+                        }
+                    }
+                    Action::Create { .. } => return Err(anyhow!("Invalid Action")),
+                };
+                for link in chain.windows(2) {
+                    check_attenuation(&link[0], &link[1])?;
+                }
+                if chain.iter().any(|d| {
+                    d.property_set
+                        .expiration
+                        .map(|exp| exp < Utc::now())
+                        .unwrap_or(false)
+                }) {
+                    return Err(anyhow!("Delegation has Expired"));
                 };
-                if let Some(ref authorized_invoker) = d.invoker {
+                if let Some(ref authorized_invoker) = leaf.invoker {
                     if authorized_invoker != &URI::String(invoker_vm.to_string()) {
                         return Err(anyhow!("Invoker not authorized"));
                     };
                 };
-                if let Some(exp) = d.property_set.expiration {
-                    if exp < Utc::now() {
-                        return Err(anyhow!("Delegation has Expired"));
-                    }
-                };
-                if !d.property_set.capability_action.contains(&match auth_token
-                    .invocation
+                let action = action_name(&auth_token.invocation.property_set.capability_action)?;
+                if !leaf
                     .property_set
                     .capability_action
+                    .iter()
+                    .any(|a| a == action)
                 {
-                    Action::List => "list".into(),
-                    Action::Put(_) => "put".into(),
-                    Action::Get(_) => "get".into(),
-                    Action::Del(_) => "del".into(),
-                    _ => return Err(anyhow!("Invalid Action")),
-                }) {
                     return Err(anyhow!("Invoked action not authorized by delegation"));
                 };
-                let mut res = d
-                    .verify(Default::default(), DID_METHODS.to_resolver())
-                    .await;
-                let mut res2 = auth_token
+                if let Some(ref paths) = leaf.property_set.paths {
+                    if !auth_token
+                        .action()
+                        .content()
+                        .iter()
+                        .all(|c| paths.iter().any(|p| scope_within(p, c)))
+                    {
+                        return Err(anyhow!("Invoked content not authorized by delegation"));
+                    }
+                };
+                let mut res = auth_token
                     .invocation
-                    .verify(Default::default(), DID_METHODS.to_resolver(), &d)
+                    .verify(Default::default(), DID_METHODS.to_resolver(), leaf)
                     .await;
-                res.append(&mut res2);
+                for d in chain.iter() {
+                    let mut link_res = d
+                        .verify(Default::default(), DID_METHODS.to_resolver())
+                        .await;
+                    res.append(&mut link_res);
+                }
                 res
             }
-            None => {
+            _ => {
                 match auth_token.invocation.property_set.capability_action {
                     Action::List | Action::Get(_) => {
                         if !self.read_delegators.contains(&invoker_vm)
@@ -177,6 +377,27 @@ impl AuthorizationPolicy<ZCAPTokens> for OrbitMetadata {
                             return Err(anyhow!("Invoker not authorized"));
                         }
                     }
+                    // delegators present the delegations they revoke, see `revokes_own`
+                    Action::Revoke(_) => {
+                        if !self.controllers.contains(&invoker_vm) {
+                            return Err(anyhow!("Invoker not authorized to revoke"));
+                        }
+                    }
+                    Action::Delete { .. } => {
+                        if !self.controllers.contains(&invoker_vm) {
+                            return Err(anyhow!("Invoker not authorized to delete the orbit"));
+                        }
+                    }
+                    Action::Snapshot(_) => {
+                        if !self.controllers.contains(&invoker_vm) {
+                            return Err(anyhow!("Invoker not authorized to snapshot the orbit"));
+                        }
+                    }
+                    Action::Update(_) => {
+                        if !self.controllers.contains(&invoker_vm) {
+                            return Err(anyhow!("Invoker not authorized to update the orbit"));
+                        }
+                    }
                     Action::Create { .. } => {}
                 };
                 auth_token
@@ -193,6 +414,30 @@ impl AuthorizationPolicy<ZCAPTokens> for OrbitMetadata {
     }
 }
 
+/// Checks the delegations of a token against the revocations published to an orbit.
+pub fn check_revocations(
+    controllers: &[DIDURL],
+    revocations: &Revocations,
+    auth_token: &ZCAPTokens,
+) -> Result<()> {
+    // a delegation may be revoked by its delegator or a controller, ancestors which were
+    // only referenced by ID can only be revoked by a controller
+    for (id, delegator_vm) in auth_token.delegation_ids()? {
+        if revocations.is_revoked(&id, controllers.iter().chain(delegator_vm.iter()))? {
+            return Err(anyhow!("Delegation {} has been revoked", id));
+        }
+    }
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl AuthorizationPolicy<ZCAPTokens> for Orbit {
+    async fn authorize(&self, auth_token: &ZCAPTokens) -> Result<()> {
+        check_revocations(self.controllers(), &self.revocations, auth_token)?;
+        self.metadata().authorize(auth_token).await
+    }
+}
+
 #[test]
 async fn basic() -> Result<()> {
     let del_str = r#"{"@context":["https://w3id.org/security/v2",{"capabilityAction":{"@id":"sec:capabilityAction","@type":"@json"}}],"id":"uuid:bac4da68-eb75-446b-8f9f-87608cbf872b","parentCapability":"kepler://zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg","invoker":"did:key:z6MkmhGnWtb1bo18Z3QfvKXFxRp6e3LHmG7i8z7ZkAa39tKA#z6MkmhGnWtb1bo18Z3QfvKXFxRp6e3LHmG7i8z7ZkAa39tKA","capabilityAction":["get","list","put","del"],"expiration":"2021-09-08T17:01:13.991Z","proof":{"@context":{"TezosMethod2021":"https://w3id.org/security#TezosMethod2021","TezosSignature2021":{"@context":{"@protected":true,"@version":1.1,"challenge":"https://w3id.org/security#challenge","created":{"@id":"http://purl.org/dc/terms/created","@type":"http://www.w3.org/2001/XMLSchema#dateTime"},"domain":"https://w3id.org/security#domain","expires":{"@id":"https://w3id.org/security#expiration","@type":"http://www.w3.org/2001/XMLSchema#dateTime"},"id":"@id","nonce":"https://w3id.org/security#nonce","proofPurpose":{"@context":{"@protected":true,"@version":1.1,"assertionMethod":{"@container":"@set","@id":"https://w3id.org/security#assertionMethod","@type":"@id"},"authentication":{"@container":"@set","@id":"https://w3id.org/security#authenticationMethod","@type":"@id"},"id":"@id","type":"@type"},"@id":"https://w3id.org/security#proofPurpose","@type":"@vocab"},"proofValue":"https://w3id.org/security#proofValue","publicKeyJwk":{"@id":"https://w3id.org/security#publicKeyJwk","@type":"@json"},"type":"@type","verificationMethod":{"@id":"https://w3id.org/security#verificationMethod","@type":"@id"}},"@id":"https://w3id.org/security#TezosSignature2021"}},"type":"TezosSignature2021","proofPurpose":"capabilityDelegation","proofValue":"edsigtXsZpmWpUqm5eNgFehmnpRbFVuJsLTTwDvrYkK8pmswpTxKFCUhDyfjjs13Gw6oGtBkgJxSMECdfvpN49pCruyokvQrg41","verificationMethod":"did:pkh:tz:tz1auyCb6BDGYqZL38UqpazAoHrztt197Tfr#TezosMethod2021","created":"2021-09-08T17:00:13.995Z","publicKeyJwk":{"alg":"EdBlake2b","crv":"Ed25519","kty":"OKP","x":"aEofZ76eliz8VX4ys9XsR1q3HXQ4sGsPT9p00kx-SLU"},"capabilityChain":[]}}"#;
@@ -201,3 +446,96 @@ async fn basic() -> Result<()> {
     assert!(res.errors.is_empty());
     Ok(())
 }
+
+#[cfg(test)]
+const TEST_DELEGATE: &str = "did:key:z6MkmhGnWtb1bo18Z3QfvKXFxRp6e3LHmG7i8z7ZkAa39tKA#z6MkmhGnWtb1bo18Z3QfvKXFxRp6e3LHmG7i8z7ZkAa39tKA";
+
+// delegated to and signed by the same key, so delegations can be chained
+#[cfg(test)]
+fn test_delegation(id: &str, parent: &str, actions: &[&str], exp: &str) -> KeplerDelegation {
+    serde_json::from_value(serde_json::json!({
+        "@context": ["https://w3id.org/security/v2"],
+        "id": id,
+        "parentCapability": parent,
+        "invoker": TEST_DELEGATE,
+        "capabilityAction": actions,
+        "expiration": exp,
+        "proof": {
+            "type": "Ed25519Signature2018",
+            "proofPurpose": "capabilityDelegation",
+            "verificationMethod": TEST_DELEGATE,
+        },
+    }))
+    .unwrap()
+}
+
+#[test]
+async fn attenuation() {
+    let root = test_delegation(
+        "urn:uuid:root",
+        "kepler://zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg",
+        &["get", "list"],
+        "2021-09-08T17:01:13.991Z",
+    );
+    let narrower = test_delegation(
+        "urn:uuid:child",
+        "urn:uuid:root",
+        &["get"],
+        "2021-09-08T17:00:13.991Z",
+    );
+    let wider = test_delegation(
+        "urn:uuid:child",
+        "urn:uuid:root",
+        &["get", "put"],
+        "2021-09-08T17:00:13.991Z",
+    );
+    let longer = test_delegation(
+        "urn:uuid:child",
+        "urn:uuid:root",
+        &["get"],
+        "2021-09-08T18:00:13.991Z",
+    );
+    let orphan = test_delegation(
+        "urn:uuid:child",
+        "urn:uuid:other",
+        &["get"],
+        "2021-09-08T17:00:13.991Z",
+    );
+    assert!(check_attenuation(&root, &narrower).is_ok());
+    assert!(check_attenuation(&root, &wider).is_err());
+    assert!(check_attenuation(&root, &longer).is_err());
+    assert!(check_attenuation(&root, &orphan).is_err());
+
+    let mut open = root.clone();
+    open.invoker = None;
+    assert!(check_attenuation(&open, &narrower).is_err());
+
+    let orbit = Cid::from_str("zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg").unwrap();
+    let other = Cid::from_str("zCT5htkeCSu7WefuBKYUidQJkRgEvEGZQrFVqYS6ZJVM6zwLCRcF").unwrap();
+    assert!(check_root(&root, &orbit).is_ok());
+    assert!(check_root(&root, &other).is_err());
+    assert!(check_root(&narrower, &orbit).is_err());
+}
+
+#[test]
+async fn own_revocations() -> Result<()> {
+    let chain = vec![test_delegation(
+        "urn:uuid:root",
+        "kepler://zCT5htkeDnBhDwQ9JsPnZKuzzQG6fSe3U44oCjZ5tkAPyNvPVXvg",
+        &["get"],
+        "2021-09-08T17:01:13.991Z",
+    )];
+    let delegator = DIDURL::from_str(TEST_DELEGATE)?;
+    let other =
+        DIDURL::from_str("did:pkh:tz:tz1auyCb6BDGYqZL38UqpazAoHrztt197Tfr#TezosMethod2021")?;
+    let root = vec!["urn:uuid:root".to_string()];
+    assert!(revokes_own(&chain, &root, &delegator));
+    assert!(!revokes_own(&chain, &root, &other));
+    assert!(!revokes_own(
+        &chain,
+        &["urn:uuid:other".to_string()],
+        &delegator
+    ));
+    assert!(!revokes_own(&chain, &[], &delegator));
+    Ok(())
+}